@@ -0,0 +1,16 @@
+#![deny(warnings, unused_crate_dependencies)]
+
+mod predeploy;
+pub use predeploy::*;
+
+mod withdrawal;
+pub use withdrawal::*;
+
+mod payer;
+pub use payer::*;
+
+mod system;
+pub use system::*;
+
+mod precompile;
+pub use precompile::*;
@@ -0,0 +1,320 @@
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use bitcoin::{consensus::encode::serialize_hex, Amount, PrivateKey, ScriptBuf, Transaction, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use tx_builder::btc::{BtcTransactionBuilder, ListUnspentRes};
+use utils::parse_sk;
+
+use crate::{PayoutInput, Withdrawal, WithdrawalStatus, WithdrawalStore};
+
+/// BTC blocks bitcoind's fee estimate targets.
+const FEE_ESTIMATE_TARGET: u16 = 6;
+
+/// How payouts are priced and batched.
+#[derive(Debug, Clone, Copy)]
+pub struct PayoutPolicy {
+    /// Fee rate in sat/vB the payouts pay at least, bitcoind's estimate is
+    /// taken when higher.
+    pub fee_rate: u64,
+    /// Withdrawals paid in one payout at most.
+    pub max_batch: usize,
+    /// BTC blocks a payout may stay unconfirmed before it is replaced with a
+    /// higher fee, zero never replaces it.
+    pub bump_after: u64,
+}
+
+/// Pays finalized withdrawals from the operator's bridge UTXOs.
+pub struct Payer {
+    builder: BtcTransactionBuilder,
+    private_key: PrivateKey,
+    script: ScriptBuf,
+    confirmations: u64,
+    policy: PayoutPolicy,
+}
+
+impl Payer {
+    pub fn new(
+        electrs_url: &str,
+        client: Arc<Client>,
+        operator_sk: &str,
+        network: &str,
+        confirmations: u64,
+        policy: PayoutPolicy,
+    ) -> Result<Self> {
+        let (private_key, address) = parse_sk(operator_sk, network)?;
+        log::info!("withdrawal operator address:{}", address);
+        Ok(Self {
+            builder: BtcTransactionBuilder::new(electrs_url, client)?,
+            private_key,
+            script: address.script_pubkey(),
+            confirmations,
+            policy,
+        })
+    }
+
+    /// Pays up to `max_batch` finalized withdrawals in one BTC transaction.
+    /// They are marked broadcast with the raw transaction before sending so a
+    /// crash never pays them twice, and are paid once it confirms. Until then
+    /// it is rebroadcast, or replaced with a higher fee after `bump_after`
+    /// blocks, and no other payout is made, which could spend the same UTXOs.
+    pub fn pay(&self, store: &mut WithdrawalStore) -> Result<Option<Txid>> {
+        let tip = self.builder.bitcoincore_client.get_block_count()?;
+        if !self.recheck(store, tip)? {
+            return Ok(None);
+        }
+
+        let mut withdrawals = store.finalized(tip, self.confirmations);
+        if withdrawals.is_empty() {
+            return Ok(None);
+        }
+        // the others are paid by the next payouts
+        withdrawals.truncate(self.policy.max_batch.max(1));
+
+        let unspents = self.builder.list_unspent(&self.script)?;
+        let fee_rate = self.fee_rate();
+        let tx = self.builder.build_payout(
+            self.private_key,
+            self.script.clone(),
+            unspents.clone(),
+            &payouts(&withdrawals),
+            fee_rate,
+        )?;
+
+        let ids = withdrawals.iter().map(|w| w.id()).collect::<Vec<_>>();
+        let status = broadcast_status(&tx, tip, fee_rate, &unspents, vec![]);
+        let txid = self.broadcast(store, &ids, &tx, status)?;
+        log::info!(
+            "payout {} for {} withdrawals at {} sat/vB",
+            txid,
+            ids.len(),
+            fee_rate
+        );
+
+        Ok(Some(txid))
+    }
+
+    /// Marks the broadcast payouts that confirmed, or one they replaced,
+    /// paid. The others are rebroadcast, or replaced when unconfirmed for
+    /// `bump_after` blocks. Returns whether none is left unconfirmed.
+    fn recheck(&self, store: &mut WithdrawalStore, tip: u64) -> Result<bool> {
+        let mut payouts = BTreeMap::<String, (WithdrawalStatus, Vec<String>)>::new();
+        for w in store.broadcast() {
+            if let WithdrawalStatus::Broadcast { ref txid, .. } = w.status {
+                payouts
+                    .entry(txid.clone())
+                    .or_insert_with(|| (w.status.clone(), vec![]))
+                    .1
+                    .push(w.id());
+            }
+        }
+
+        let client = &self.builder.bitcoincore_client;
+        let mut settled = true;
+        for (txid, (status, ids)) in payouts {
+            let WithdrawalStatus::Broadcast {
+                ref raw,
+                height,
+                ref inputs,
+                ref replaced,
+                ..
+            } = status
+            else {
+                continue;
+            };
+            if let Some(paid) = [&txid]
+                .into_iter()
+                .chain(replaced.iter())
+                .find(|txid| self.confirmed(txid))
+            {
+                log::info!("payout {} confirmed", paid);
+                store.set_status(&ids, WithdrawalStatus::Paid(paid.clone()))?;
+                continue;
+            }
+
+            settled = false;
+            // payouts broadcast before the inputs were recorded can not be
+            // replaced
+            if self.policy.bump_after > 0
+                && !inputs.is_empty()
+                && tip >= height + self.policy.bump_after
+            {
+                match self.bump(store, &ids, tip, status.clone()) {
+                    Ok(bumped) => {
+                        log::info!("payout {} replaced by {}", txid, bumped);
+                        continue;
+                    }
+                    Err(e) => log::warn!("replace payout {} error:{}", txid, e),
+                }
+            }
+            if let Err(e) = client.send_raw_transaction(raw.as_str()) {
+                log::warn!("rebroadcast payout {} error:{}", txid, e);
+            }
+        }
+        Ok(settled)
+    }
+
+    /// Replaces the payout of `ids` with one paying a higher fee rate. It
+    /// spends the same outputs first so the two conflict, and never those
+    /// created by the payouts it replaces.
+    fn bump(
+        &self,
+        store: &mut WithdrawalStore,
+        ids: &[String],
+        tip: u64,
+        status: WithdrawalStatus,
+    ) -> Result<Txid> {
+        let WithdrawalStatus::Broadcast {
+            txid,
+            fee_rate,
+            inputs,
+            mut replaced,
+            ..
+        } = status
+        else {
+            return Err(anyhow!("payout not broadcast"));
+        };
+        replaced.push(txid);
+        // BIP125 wants a higher fee rate and the relay fee on top
+        let fee_rate = self
+            .fee_rate()
+            .max(fee_rate + fee_rate / 4)
+            .max(fee_rate + 1);
+
+        let mut unspents = inputs
+            .iter()
+            .map(|input| {
+                Ok(ListUnspentRes {
+                    height: 0,
+                    tx_hash: Txid::from_str(&input.txid)?,
+                    tx_pos: input.vout as usize,
+                    value: input.value,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let spent = unspents
+            .iter()
+            .map(|u| (u.tx_hash, u.tx_pos))
+            .collect::<Vec<_>>();
+        unspents.extend(
+            self.builder
+                .list_unspent(&self.script)?
+                .into_iter()
+                .filter(|u| !spent.contains(&(u.tx_hash, u.tx_pos)))
+                .filter(|u| !replaced.contains(&u.tx_hash.to_string())),
+        );
+
+        let withdrawals = ids
+            .iter()
+            .map(|id| {
+                store
+                    .get(id)
+                    .cloned()
+                    .ok_or(anyhow!("withdrawal {} not found", id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tx = self.builder.build_payout(
+            self.private_key,
+            self.script.clone(),
+            unspents.clone(),
+            &payouts(&withdrawals),
+            fee_rate,
+        )?;
+
+        let status = broadcast_status(&tx, tip, fee_rate, &unspents, replaced);
+        self.broadcast(store, ids, &tx, status)
+    }
+
+    /// Marks the withdrawals `ids` broadcast in `tx` before sending it.
+    fn broadcast(
+        &self,
+        store: &mut WithdrawalStore,
+        ids: &[String],
+        tx: &Transaction,
+        status: WithdrawalStatus,
+    ) -> Result<Txid> {
+        let txid = tx.txid();
+        store.set_status(ids, status)?;
+        self.builder
+            .bitcoincore_client
+            .send_raw_transaction(tx)
+            .map_err(|e| {
+                anyhow!(
+                    "send payout {} error:{}, raw tx:{}",
+                    txid,
+                    e,
+                    serialize_hex(tx)
+                )
+            })?;
+        Ok(txid)
+    }
+
+    fn confirmed(&self, txid: &str) -> bool {
+        Txid::from_str(txid).is_ok_and(|txid| {
+            self.builder
+                .bitcoincore_client
+                .get_raw_transaction_info(&txid, None)
+                .ok()
+                .and_then(|info| info.confirmations)
+                .is_some_and(|confirmations| confirmations > 0)
+        })
+    }
+
+    /// Fee rate in sat/vB bitcoind estimates for the payouts, at least the
+    /// configured one.
+    fn fee_rate(&self) -> u64 {
+        self.builder
+            .bitcoincore_client
+            .estimate_smart_fee(FEE_ESTIMATE_TARGET, None)
+            .ok()
+            .and_then(|estimate| estimate.fee_rate)
+            // estimated in BTC/kvB
+            .map(|rate| rate.to_sat().div_ceil(1000))
+            .unwrap_or_default()
+            .max(self.policy.fee_rate)
+    }
+}
+
+fn payouts(withdrawals: &[Withdrawal]) -> Vec<(ScriptBuf, Amount)> {
+    withdrawals
+        .iter()
+        .map(|w| {
+            (
+                ScriptBuf::from_bytes(w.script.to_vec()),
+                Amount::from_sat(w.sat),
+            )
+        })
+        .collect()
+}
+
+fn broadcast_status(
+    tx: &Transaction,
+    height: u64,
+    fee_rate: u64,
+    unspents: &[ListUnspentRes],
+    replaced: Vec<String>,
+) -> WithdrawalStatus {
+    let inputs = tx
+        .input
+        .iter()
+        .filter_map(|input| {
+            let outpoint = input.previous_output;
+            unspents
+                .iter()
+                .find(|u| u.tx_hash == outpoint.txid && u.tx_pos == outpoint.vout as usize)
+                .map(|u| PayoutInput {
+                    txid: outpoint.txid.to_string(),
+                    vout: outpoint.vout,
+                    value: u.value,
+                })
+        })
+        .collect();
+    WithdrawalStatus::Broadcast {
+        txid: tx.txid().to_string(),
+        raw: serialize_hex(tx),
+        height,
+        fee_rate,
+        inputs,
+        replaced,
+    }
+}
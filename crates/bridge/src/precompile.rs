@@ -0,0 +1,543 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use bitcoin::{
+    absolute::LockTime,
+    consensus::{encode::deserialize_partial, serialize},
+    ecdsa,
+    hashes::{sha256, sha256d, Hash, HashEngine},
+    opcodes::{all::OP_RETURN, OP_0},
+    script::Builder,
+    secp256k1::{
+        ecdsa::{RecoverableSignature, RecoveryId},
+        schnorr, Message, Secp256k1, XOnlyPublicKey,
+    },
+    sighash::{Prevouts, SighashCache},
+    sign_message::{signed_msg_hash, MessageSignature},
+    taproot,
+    transaction::Version,
+    Amount, OutPoint, PublicKey, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use rt_evm::model::types::{H160, U256};
+use utils::HeaderStore;
+
+/// A contract implemented natively, `execute` returns the output and the gas
+/// used.
+pub trait Precompile: Send + Sync {
+    fn address(&self) -> H160;
+    fn execute(&self, input: &[u8], gas_limit: u64) -> Result<(Vec<u8>, u64)>;
+}
+
+pub const BTC_HEADER_PRECOMPILE: H160 = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x00,
+]);
+
+pub const SCHNORR_PRECOMPILE: H160 = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x01,
+]);
+
+pub const BTC_MESSAGE_PRECOMPILE: H160 = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x02,
+]);
+
+const HEADER_GAS: u64 = 3000;
+const PROOF_STEP_GAS: u64 = 200;
+const SCHNORR_GAS: u64 = 4000;
+const BIP137_GAS: u64 = 6000;
+const BIP322_GAS: u64 = 10000;
+
+fn check_gas(gas: u64, gas_limit: u64) -> Result<u64> {
+    if gas > gas_limit {
+        Err(anyhow!("out of gas:{} {}", gas, gas_limit))
+    } else {
+        Ok(gas)
+    }
+}
+
+fn word(input: &[u8], index: usize) -> Result<&[u8]> {
+    input
+        .get(index * 32..(index + 1) * 32)
+        .ok_or(anyhow!("input too short"))
+}
+
+/// Serves the BTC headers the indexer stored, up to the block being executed.
+///
+/// * `0x00 ++ height` returns the 80 byte header at `height`.
+/// * `0x01 ++ height ++ txid ++ index ++ proof...` returns a word, 1 when the
+///   merkle branch `proof` of the transaction at `index` leads to the merkle
+///   root of the header at `height`. Hashes are in internal byte order.
+pub struct BtcHeaderPrecompile {
+    headers: Arc<RwLock<HeaderStore>>,
+}
+
+impl BtcHeaderPrecompile {
+    pub fn new(headers: Arc<RwLock<HeaderStore>>) -> Self {
+        Self { headers }
+    }
+}
+
+impl Precompile for BtcHeaderPrecompile {
+    fn address(&self) -> H160 {
+        BTC_HEADER_PRECOMPILE
+    }
+
+    fn execute(&self, input: &[u8], gas_limit: u64) -> Result<(Vec<u8>, u64)> {
+        let (selector, args) = input.split_first().ok_or(anyhow!("input empty"))?;
+        let height = U256::from_big_endian(word(args, 0)?);
+        if height > U256::from(u64::MAX) {
+            return Err(anyhow!("height overflow"));
+        }
+
+        let headers = self.headers.read().map_err(|e| anyhow!("{}", e))?;
+        let header = headers
+            .get(height.as_u64())
+            .ok_or(anyhow!("header {} not found", height))?;
+
+        match selector {
+            0x00 => Ok((serialize(header), check_gas(HEADER_GAS, gas_limit)?)),
+            0x01 => {
+                let proof = args.get(96..).ok_or(anyhow!("input too short"))?;
+                if proof.len() % 32 != 0 {
+                    return Err(anyhow!("proof length error:{}", proof.len()));
+                }
+                let gas = check_gas(
+                    HEADER_GAS + PROOF_STEP_GAS * (proof.len() / 32) as u64,
+                    gas_limit,
+                )?;
+
+                let mut hash = <[u8; 32]>::try_from(word(args, 1)?)?;
+                let mut index = U256::from_big_endian(word(args, 2)?);
+                for sibling in proof.chunks_exact(32) {
+                    let mut data = Vec::with_capacity(64);
+                    if index.bit(0) {
+                        data.extend_from_slice(sibling);
+                        data.extend_from_slice(&hash);
+                    } else {
+                        data.extend_from_slice(&hash);
+                        data.extend_from_slice(sibling);
+                    }
+                    hash = sha256d::Hash::hash(&data).to_byte_array();
+                    index >>= 1;
+                }
+
+                let mut ret = vec![0u8; 32];
+                if index.is_zero() && hash == header.merkle_root.to_byte_array() {
+                    ret[31] = 1;
+                }
+                Ok((ret, gas))
+            }
+            _ => Err(anyhow!("selector error:{}", selector)),
+        }
+    }
+}
+
+fn bool_word(value: bool) -> Vec<u8> {
+    let mut ret = vec![0u8; 32];
+    ret[31] = value as u8;
+    ret
+}
+
+/// BIP340 verification, `pubkey ++ message ++ signature` of 32, 32 and 64
+/// bytes returns a word, 1 when the signature is valid.
+pub struct SchnorrPrecompile;
+
+impl Precompile for SchnorrPrecompile {
+    fn address(&self) -> H160 {
+        SCHNORR_PRECOMPILE
+    }
+
+    fn execute(&self, input: &[u8], gas_limit: u64) -> Result<(Vec<u8>, u64)> {
+        let gas = check_gas(SCHNORR_GAS, gas_limit)?;
+        if input.len() != 128 {
+            return Err(anyhow!("input length error:{}", input.len()));
+        }
+
+        let valid = match (
+            XOnlyPublicKey::from_slice(&input[..32]),
+            Message::from_slice(&input[32..64]),
+            schnorr::Signature::from_slice(&input[64..]),
+        ) {
+            (Ok(pk), Ok(msg), Ok(sig)) => Secp256k1::verification_only()
+                .verify_schnorr(&sig, &msg, &pk)
+                .is_ok(),
+            _ => false,
+        };
+        Ok((bool_word(valid), gas))
+    }
+}
+
+/// Bitcoin signed message verification.
+///
+/// * `0x00 ++ signature ++ message` checks a 65 byte BIP137 signature and
+///   returns the hash160 of the signing key as a right aligned word, to
+///   compare with the p2pkh or p2wpkh program of the signer. Headers 27-30
+///   are p2pkh of an uncompressed key, 31-34 p2pkh, 35-38 p2sh-p2wpkh and
+///   39-42 p2wpkh of a compressed key, others are refused.
+/// * `0x01 ++ script_len ++ script_pubkey ++ witness ++ message` checks a
+///   BIP322 simple signature of a p2wpkh or p2tr key path address, the
+///   witness consensus encoded, and returns a word, 1 when it is valid.
+pub struct BtcMessagePrecompile;
+
+impl Precompile for BtcMessagePrecompile {
+    fn address(&self) -> H160 {
+        BTC_MESSAGE_PRECOMPILE
+    }
+
+    fn execute(&self, input: &[u8], gas_limit: u64) -> Result<(Vec<u8>, u64)> {
+        let (selector, args) = input.split_first().ok_or(anyhow!("input empty"))?;
+        match selector {
+            0x00 => {
+                let gas = check_gas(BIP137_GAS, gas_limit)?;
+                if args.len() < 65 {
+                    return Err(anyhow!("input too short"));
+                }
+                let message = std::str::from_utf8(&args[65..])?;
+                let compressed = match args[0] {
+                    27..=30 => false,
+                    31..=42 => true,
+                    header => return Err(anyhow!("signature header error:{}", header)),
+                };
+                let recovery_id = RecoveryId::from_i32(((args[0] - 27) % 4) as i32)?;
+                let signature = MessageSignature {
+                    signature: RecoverableSignature::from_compact(&args[1..65], recovery_id)?,
+                    compressed,
+                };
+                let pk = signature
+                    .recover_pubkey(&Secp256k1::verification_only(), signed_msg_hash(message))?;
+
+                let mut ret = vec![0u8; 32];
+                ret[12..].copy_from_slice(pk.pubkey_hash().as_byte_array());
+                Ok((ret, gas))
+            }
+            0x01 => {
+                let gas = check_gas(BIP322_GAS, gas_limit)?;
+                let (len, args) = args.split_first().ok_or(anyhow!("input too short"))?;
+                let script_pubkey = Script::from_bytes(
+                    args.get(..*len as usize)
+                        .ok_or(anyhow!("input too short"))?,
+                );
+                let (witness, consumed) = deserialize_partial::<Witness>(&args[*len as usize..])?;
+                let message = &args[*len as usize + consumed..];
+
+                Ok((
+                    bool_word(bip322_verify(script_pubkey, &witness, message)?),
+                    gas,
+                ))
+            }
+            _ => Err(anyhow!("selector error:{}", selector)),
+        }
+    }
+}
+
+fn bip322_verify(script_pubkey: &Script, witness: &Witness, message: &[u8]) -> Result<bool> {
+    let tag = sha256::Hash::hash(b"BIP0322-signed-message");
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    engine.input(message);
+    let message_hash = sha256::Hash::from_engine(engine);
+
+    let to_spend = Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0xFFFFFFFF,
+            },
+            script_sig: Builder::new()
+                .push_opcode(OP_0)
+                .push_slice(message_hash.to_byte_array())
+                .into_script(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    };
+    let to_sign = Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    };
+
+    let secp = Secp256k1::verification_only();
+    let mut sighasher = SighashCache::new(&to_sign);
+    if script_pubkey.is_p2wpkh() {
+        let (sig, pk) = match (witness.len(), witness.nth(0), witness.nth(1)) {
+            (2, Some(sig), Some(pk)) => (
+                ecdsa::Signature::from_slice(sig)?,
+                PublicKey::from_slice(pk)?,
+            ),
+            _ => return Ok(false),
+        };
+        let wpubkey_hash = pk
+            .wpubkey_hash()
+            .ok_or(anyhow!("uncompressed public key"))?;
+        if ScriptBuf::new_p2wpkh(&wpubkey_hash).as_script() != script_pubkey {
+            return Ok(false);
+        }
+        let sighash =
+            sighasher.p2wpkh_signature_hash(0, script_pubkey, Amount::ZERO, sig.hash_ty)?;
+        Ok(secp
+            .verify_ecdsa(&Message::from(sighash), &sig.sig, &pk.inner)
+            .is_ok())
+    } else if script_pubkey.is_p2tr() {
+        let sig = match (witness.len(), witness.nth(0)) {
+            (1, Some(sig)) => taproot::Signature::from_slice(sig)?,
+            _ => return Ok(false),
+        };
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+        let sighash = sighasher.taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&to_spend.output),
+            sig.hash_ty,
+        )?;
+        Ok(secp
+            .verify_schnorr(&sig.sig, &Message::from(sighash), &output_key)
+            .is_ok())
+    } else {
+        Err(anyhow!("unsupported script:{}", script_pubkey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use bitcoin::{block::Header, consensus::deserialize};
+    use ethers::utils::hex;
+
+    use super::*;
+
+    // BTC block 100000
+    const HEADER: &str = "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200000000006657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f337221b4d4c86041b0f2b5710";
+    // its third transaction 6359f086..., and the merkle branch of it, in
+    // internal byte order
+    const TXID: &str = "c46e239ab7d28e2c019b6d66ad8fae98a56ef1f21aeecb94d1b1718186f05963";
+    const PROOF: [&str; 2] = [
+        "1d0cb83721529a062d9675b98d6e5c587e4a770fc84ed00abc5a5de04568a6e9",
+        "15b88c5107195bf09eb9da89b83d95b3d070079a3c5c5d3d17d0dcd873fbdacc",
+    ];
+
+    fn u256_word(value: u64) -> Vec<u8> {
+        let mut ret = vec![0u8; 32];
+        U256::from(value).to_big_endian(&mut ret);
+        ret
+    }
+
+    fn header_precompile(name: &str) -> (BtcHeaderPrecompile, std::path::PathBuf) {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let header: Header = deserialize(&hex::decode(HEADER).unwrap()).unwrap();
+        let headers = HeaderStore::load(path.clone(), 100000, 100001, |_| Ok(header)).unwrap();
+        (
+            BtcHeaderPrecompile::new(Arc::new(RwLock::new(headers))),
+            path,
+        )
+    }
+
+    fn proof_input(index: u64) -> Vec<u8> {
+        let mut input = vec![0x01];
+        input.extend(u256_word(100000));
+        input.extend(hex::decode(TXID).unwrap());
+        input.extend(u256_word(index));
+        for sibling in PROOF {
+            input.extend(hex::decode(sibling).unwrap());
+        }
+        input
+    }
+
+    #[test]
+    fn btc_header() {
+        let (precompile, path) = header_precompile("btc_header_precompile_header.meta");
+
+        let mut input = vec![0x00];
+        input.extend(u256_word(100000));
+        let (ret, gas) = precompile.execute(&input, HEADER_GAS).unwrap();
+        assert_eq!(ret, hex::decode(HEADER).unwrap());
+        assert_eq!(gas, HEADER_GAS);
+        assert!(precompile.execute(&input, HEADER_GAS - 1).is_err());
+
+        let mut input = vec![0x00];
+        input.extend(u256_word(100001));
+        assert!(precompile.execute(&input, HEADER_GAS).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merkle_proof() {
+        let (precompile, path) = header_precompile("btc_header_precompile_proof.meta");
+
+        let gas = HEADER_GAS + PROOF_STEP_GAS * 2;
+        assert_eq!(
+            precompile.execute(&proof_input(2), gas).unwrap(),
+            (bool_word(true), gas)
+        );
+        assert_eq!(
+            precompile.execute(&proof_input(3), gas).unwrap(),
+            (bool_word(false), gas)
+        );
+        // an index beyond the branch
+        assert_eq!(
+            precompile.execute(&proof_input(6), gas).unwrap(),
+            (bool_word(false), gas)
+        );
+        assert!(precompile.execute(&proof_input(2), gas - 1).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    fn schnorr_input(pk: &str, msg: &str, sig: &str) -> Vec<u8> {
+        [pk, msg, sig]
+            .iter()
+            .flat_map(|s| hex::decode(s).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn schnorr_bip340_vectors() {
+        let vectors = [
+            (
+                "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+            ),
+            (
+                "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+                "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+                "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+            ),
+        ];
+        for (pk, msg, sig) in vectors {
+            let mut input = schnorr_input(pk, msg, sig);
+            assert_eq!(
+                SchnorrPrecompile.execute(&input, SCHNORR_GAS).unwrap(),
+                (bool_word(true), SCHNORR_GAS)
+            );
+
+            input[127] ^= 1;
+            assert_eq!(
+                SchnorrPrecompile.execute(&input, SCHNORR_GAS).unwrap(),
+                (bool_word(false), SCHNORR_GAS)
+            );
+            assert!(SchnorrPrecompile
+                .execute(&input[..127], SCHNORR_GAS)
+                .is_err());
+            assert!(SchnorrPrecompile.execute(&input, SCHNORR_GAS - 1).is_err());
+        }
+    }
+
+    // p2wpkh of bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l
+    const BIP322_SCRIPT: &str = "00142b05d564e6a7a33c087f16e0f730d1440123799d";
+
+    fn bip322_input(witness: &str, message: &[u8]) -> Vec<u8> {
+        let script = hex::decode(BIP322_SCRIPT).unwrap();
+        let mut input = vec![0x01, script.len() as u8];
+        input.extend(script);
+        input.extend(hex::decode(witness).unwrap());
+        input.extend_from_slice(message);
+        input
+    }
+
+    #[test]
+    fn message_bip322_vectors() {
+        let vectors: [(&[u8], &str); 2] = [
+            (
+                b"",
+                "024730440220336801010aaf657d79662cac98a990a43ac6f376af2c84f8f76401ccb9d0231602201693a4e683db4a91944ca5cb11527840366daf583a2c695fccf8e93483b52e34012102c7f12003196442943d8588e01aee840423cc54fc1521526a3b85c2b0cbd58872",
+            ),
+            (
+                b"Hello World",
+                "0247304402206517c8637a7bfc3a154edcba6196d64bbd5b73955cb7da7d1626bcdde466c364022022bf10d19fc0bb69b4596e306b362acaa835293cf693bb176f7324b531f5afec012102c7f12003196442943d8588e01aee840423cc54fc1521526a3b85c2b0cbd58872",
+            ),
+        ];
+        for (message, witness) in vectors {
+            assert_eq!(
+                BtcMessagePrecompile
+                    .execute(&bip322_input(witness, message), BIP322_GAS)
+                    .unwrap(),
+                (bool_word(true), BIP322_GAS)
+            );
+        }
+
+        // the signature of one message does not verify the other
+        assert_eq!(
+            BtcMessagePrecompile
+                .execute(&bip322_input(vectors[0].1, vectors[1].0), BIP322_GAS)
+                .unwrap(),
+            (bool_word(false), BIP322_GAS)
+        );
+    }
+
+    // "Hello World" signed with an arbitrary key, headers of the four BIP137
+    // ranges over the same signature
+    const BIP137_SIGNATURE: &str = "1b7f7cecea32b59e32c728d1fdd8bfe5ea157fc290b942687a443fc45aaca71821eea154b2a79eb46314f78b39817f73e98c8845f131c2a3511b306a659bb873";
+    const UNCOMPRESSED_KEY_HASH: &str = "211b74ca4686f81efda5641767fc84ef16dafe0b";
+    const COMPRESSED_KEY_HASH: &str = "bbc1e42a39d05a4cc61752d6963b7f69d09bb27b";
+
+    fn bip137_input(header: u8, message: &[u8]) -> Vec<u8> {
+        let mut input = vec![0x00, header];
+        input.extend(hex::decode(BIP137_SIGNATURE).unwrap());
+        input.extend_from_slice(message);
+        input
+    }
+
+    fn key_hash_word(key_hash: &str) -> Vec<u8> {
+        let mut ret = vec![0u8; 12];
+        ret.extend(hex::decode(key_hash).unwrap());
+        ret
+    }
+
+    #[test]
+    fn message_bip137_headers() {
+        let vectors = [
+            (27, UNCOMPRESSED_KEY_HASH),
+            (31, COMPRESSED_KEY_HASH),
+            (35, COMPRESSED_KEY_HASH),
+            (39, COMPRESSED_KEY_HASH),
+        ];
+        for (header, key_hash) in vectors {
+            assert_eq!(
+                BtcMessagePrecompile
+                    .execute(&bip137_input(header, b"Hello World"), BIP137_GAS)
+                    .unwrap(),
+                (key_hash_word(key_hash), BIP137_GAS)
+            );
+        }
+
+        // another message recovers another key
+        let (ret, _) = BtcMessagePrecompile
+            .execute(&bip137_input(31, b"Hello World!"), BIP137_GAS)
+            .unwrap();
+        assert_ne!(ret, key_hash_word(COMPRESSED_KEY_HASH));
+
+        for header in [26, 43, 0xff] {
+            assert!(BtcMessagePrecompile
+                .execute(&bip137_input(header, b"Hello World"), BIP137_GAS)
+                .is_err());
+        }
+        assert!(BtcMessagePrecompile
+            .execute(&bip137_input(31, b"Hello World"), BIP137_GAS - 1)
+            .is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use ethers::utils::{get_contract_address, keccak256};
+use rt_evm::model::types::{
+    DepositTransaction, SignedTransaction, TransactionAction, H160, H256, U256,
+};
+
+use crate::SYSTEM_ADDRESS;
+
+/// System account deploying the predeploys, its nonce counts those created.
+pub const PREDEPLOY_DEPLOYER: H160 = H160([
+    0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad,
+    0xde, 0xad, 0x00, 0x01,
+]);
+
+const PREDEPLOY_GAS: u64 = 200000;
+
+/// Predeploys are created in this order, by the deployer nonce they get.
+fn predeploy_runtime_codes() -> Vec<Vec<u8>> {
+    vec![bridge_runtime_code(), l1_block_runtime_code()]
+}
+
+pub fn bridge_address() -> H160 {
+    get_contract_address(PREDEPLOY_DEPLOYER, 0)
+}
+
+pub fn l1_block_address() -> H160 {
+    get_contract_address(PREDEPLOY_DEPLOYER, 1)
+}
+
+/// topic0 of `Withdrawal(address indexed from, uint256 indexed value, bytes script)`,
+/// `script` is the raw calldata: the BTC script_pubkey to pay.
+pub fn withdrawal_topic() -> H256 {
+    H256::from(keccak256("Withdrawal(address,uint256,bytes)"))
+}
+
+/// The bridge keeps every value sent to it, which burns it, and logs a
+/// withdrawal with the calldata as payout script. Calls without value revert.
+fn bridge_runtime_code() -> Vec<u8> {
+    let mut code = vec![
+        0x34, // CALLVALUE
+        0x15, // ISZERO
+        0x60, 0x33, // PUSH1 revert
+        0x57, // JUMPI
+        0x36, // CALLDATASIZE
+        0x60, 0x00, // PUSH1 0
+        0x60, 0x00, // PUSH1 0
+        0x37, // CALLDATACOPY
+        0x34, // CALLVALUE
+        0x33, // CALLER
+        0x7f, // PUSH32 topic
+    ];
+    code.extend_from_slice(withdrawal_topic().as_bytes());
+    code.extend_from_slice(&[
+        0x36, // CALLDATASIZE
+        0x60, 0x00, // PUSH1 0
+        0xa3, // LOG3
+        0x00, // STOP
+        0x5b, // JUMPDEST revert
+        0x60, 0x00, // PUSH1 0
+        0x60, 0x00, // PUSH1 0
+        0xfd, // REVERT
+    ]);
+    code
+}
+
+/// Holds the attributes of the BTC block the current EVM block is anchored
+/// to, in slots 0 to 3: height, block hash, timestamp and median fee rate in
+/// sat/vB. The system account writes them as four words of calldata, any
+/// other call returns them ABI encoded as `(uint256, bytes32, uint256, uint256)`.
+fn l1_block_runtime_code() -> Vec<u8> {
+    let mut code = vec![
+        0x33, // CALLER
+        0x73, // PUSH20 system address
+    ];
+    code.extend_from_slice(SYSTEM_ADDRESS.as_bytes());
+    code.extend_from_slice(&[
+        0x14, // EQ
+        0x60, 0x37, // PUSH1 set
+        0x57, // JUMPI
+        0x60, 0x00, 0x54, 0x60, 0x00, 0x52, // MSTORE(0x00, SLOAD(0))
+        0x60, 0x01, 0x54, 0x60, 0x20, 0x52, // MSTORE(0x20, SLOAD(1))
+        0x60, 0x02, 0x54, 0x60, 0x40, 0x52, // MSTORE(0x40, SLOAD(2))
+        0x60, 0x03, 0x54, 0x60, 0x60, 0x52, // MSTORE(0x60, SLOAD(3))
+        0x60, 0x80, 0x60, 0x00, 0xf3, // RETURN(0, 0x80)
+        0x5b, // JUMPDEST set
+        0x60, 0x00, 0x35, 0x60, 0x00, 0x55, // SSTORE(0, CALLDATALOAD(0x00))
+        0x60, 0x20, 0x35, 0x60, 0x01, 0x55, // SSTORE(1, CALLDATALOAD(0x20))
+        0x60, 0x40, 0x35, 0x60, 0x02, 0x55, // SSTORE(2, CALLDATALOAD(0x40))
+        0x60, 0x60, 0x35, 0x60, 0x03, 0x55, // SSTORE(3, CALLDATALOAD(0x60))
+        0x00, // STOP
+    ]);
+    code
+}
+
+fn init_code(runtime: Vec<u8>) -> Vec<u8> {
+    let len = runtime.len() as u8;
+    let mut code = vec![
+        0x60, len, // PUSH1 len
+        0x60, 0x0c, // PUSH1 runtime offset
+        0x60, 0x00, // PUSH1 0
+        0x39, // CODECOPY
+        0x60, len, // PUSH1 len
+        0x60, 0x00, // PUSH1 0
+        0xf3, // RETURN
+    ];
+    code.extend(runtime);
+    code
+}
+
+/// System deposits creating the predeploys the deployer has not created yet,
+/// `deployed` being its nonce.
+pub fn predeploy_txs(deployed: U256, chain_id: u64) -> Vec<SignedTransaction> {
+    predeploy_runtime_codes()
+        .into_iter()
+        .enumerate()
+        .skip(deployed.as_usize())
+        .map(|(nonce, runtime)| {
+            let data = init_code(runtime);
+            let deposit_tx = DepositTransaction {
+                nonce: U256::from(nonce),
+                source_hash: H256::from(keccak256(&data)),
+                from: PREDEPLOY_DEPLOYER,
+                action: TransactionAction::Create,
+                mint: None,
+                value: U256::zero(),
+                gas_limit: U256::from(PREDEPLOY_GAS),
+                is_system_tx: true,
+                data,
+            };
+            SignedTransaction::from_deposit_tx(deposit_tx, chain_id)
+        })
+        .collect()
+}
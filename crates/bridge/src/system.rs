@@ -0,0 +1,331 @@
+use ethers::utils::keccak256;
+use rt_evm::model::types::{
+    DepositTransaction, Receipt, SignedTransaction, TransactionAction, UnsignedTransaction, H160,
+    H256, U256,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::l1_block_address;
+
+/// Sender of the system deposits, each one mints the value it transfers.
+pub const SYSTEM_ADDRESS: H160 = H160([
+    0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad, 0xde, 0xad,
+    0xde, 0xad, 0x00, 0x00,
+]);
+
+const SYSTEM_TRANSFER_GAS: u64 = 21000;
+const L1_ATTRIBUTES_GAS: u64 = 150000;
+
+/// Wei credited to `to` by a system deposit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credit {
+    pub source_hash: H256,
+    pub to: H160,
+    pub value: U256,
+}
+
+/// Gas the user deposits of a block paid for on BTC but left unused, priced
+/// at `base_fee`, and the gas they used credited to `fee_recipient` in one
+/// credit. `receipts` are those of `txs`, in the same order.
+pub fn gas_refunds(
+    txs: &[SignedTransaction],
+    receipts: &[Receipt],
+    base_fee: U256,
+    fee_recipient: Option<H160>,
+) -> Vec<Credit> {
+    let mut credits = vec![];
+    let mut used_fee = U256::zero();
+    let mut fee_preimage = vec![];
+    for (tx, receipt) in txs.iter().zip(receipts.iter()) {
+        let deposit_tx = match &tx.transaction.unsigned {
+            UnsignedTransaction::Deposit(tx) if !tx.is_system_tx => tx,
+            _ => continue,
+        };
+        let used_gas = receipt.used_gas.min(deposit_tx.gas_limit);
+        used_fee = used_fee.saturating_add(used_gas.saturating_mul(base_fee));
+        fee_preimage.extend_from_slice(tx.transaction.hash.as_bytes());
+
+        let value = deposit_tx
+            .gas_limit
+            .saturating_sub(used_gas)
+            .saturating_mul(base_fee);
+        if value.is_zero() {
+            continue;
+        }
+
+        let mut preimage = tx.transaction.hash.as_bytes().to_vec();
+        preimage.extend_from_slice(b"refund");
+        credits.push(Credit {
+            source_hash: H256::from(keccak256(preimage)),
+            to: deposit_tx.from,
+            value,
+        });
+    }
+
+    if let Some(to) = fee_recipient.filter(|_| !used_fee.is_zero()) {
+        fee_preimage.extend_from_slice(b"fee");
+        credits.push(Credit {
+            source_hash: H256::from(keccak256(fee_preimage)),
+            to,
+            value: used_fee,
+        });
+    }
+    credits
+}
+
+/// Gas fee a deposit reserves out of its BTC fee, the part of it left unused
+/// is refunded to the sender and the rest credited to the fee recipient once
+/// it is executed.
+pub fn reserved_fee(tx: &SignedTransaction, base_fee: U256) -> U256 {
+    tx.transaction.unsigned.gas_limit().saturating_mul(base_fee)
+}
+
+/// Credits a deposit left out of its block the gas fee it reserved, to
+/// `to`.
+pub fn excluded_credit(tx: &SignedTransaction, to: H160, base_fee: U256) -> Credit {
+    let mut preimage = tx.transaction.hash.as_bytes().to_vec();
+    preimage.extend_from_slice(b"excluded");
+    Credit {
+        source_hash: H256::from(keccak256(preimage)),
+        to,
+        value: reserved_fee(tx, base_fee),
+    }
+}
+
+/// Credits the BTC fees collected at `height` to the fee recipient, net of
+/// the gas fee the deposits reserved.
+pub fn fee_credit(height: u64, to: H160, value: U256) -> Credit {
+    let mut preimage = height.to_be_bytes().to_vec();
+    preimage.extend_from_slice(b"fee");
+    Credit {
+        source_hash: H256::from(keccak256(preimage)),
+        to,
+        value,
+    }
+}
+
+/// Credits the value a BTC transaction locked to the bridge to its sender.
+pub fn mint_credit(btc_txid: H256, to: H160, value: U256) -> Credit {
+    let mut preimage = btc_txid.as_bytes().to_vec();
+    preimage.extend_from_slice(b"mint");
+    Credit {
+        source_hash: H256::from(keccak256(preimage)),
+        to,
+        value,
+    }
+}
+
+pub fn system_transfer_tx(nonce: U256, credit: &Credit, chain_id: u64) -> SignedTransaction {
+    let deposit_tx = DepositTransaction {
+        nonce,
+        source_hash: credit.source_hash,
+        from: SYSTEM_ADDRESS,
+        action: TransactionAction::Call(credit.to),
+        mint: Some(credit.value),
+        value: credit.value,
+        gas_limit: U256::from(SYSTEM_TRANSFER_GAS),
+        is_system_tx: true,
+        data: vec![],
+    };
+    SignedTransaction::from_deposit_tx(deposit_tx, chain_id)
+}
+
+/// Gas of the system deposits of an EVM block, the L1 attributes and
+/// `transfers` credits.
+pub fn system_gas(transfers: usize) -> U256 {
+    U256::from(L1_ATTRIBUTES_GAS) + U256::from(SYSTEM_TRANSFER_GAS) * U256::from(transfers)
+}
+
+/// System deposit writing the attributes of the anchoring BTC block into the
+/// `index`th EVM block produced from it, the L1 block predeploy.
+pub fn l1_attributes_tx(
+    nonce: U256,
+    height: u64,
+    hash: H256,
+    time: u64,
+    median_fee_rate: u64,
+    index: u32,
+    chain_id: u64,
+) -> SignedTransaction {
+    let mut data = vec![0u8; 128];
+    U256::from(height).to_big_endian(&mut data[0..32]);
+    data[32..64].copy_from_slice(hash.as_bytes());
+    U256::from(time).to_big_endian(&mut data[64..96]);
+    U256::from(median_fee_rate).to_big_endian(&mut data[96..128]);
+    // the EVM blocks of a BTC block get the same attributes
+    let mut preimage = data.clone();
+    preimage.extend_from_slice(&index.to_be_bytes());
+
+    let deposit_tx = DepositTransaction {
+        nonce,
+        source_hash: H256::from(keccak256(preimage)),
+        from: SYSTEM_ADDRESS,
+        action: TransactionAction::Call(l1_block_address()),
+        mint: None,
+        value: U256::zero(),
+        gas_limit: U256::from(L1_ATTRIBUTES_GAS),
+        is_system_tx: true,
+        data,
+    };
+    SignedTransaction::from_deposit_tx(deposit_tx, chain_id)
+}
+
+/// Prevrandao of the `index`th EVM block produced from the BTC block `hash`,
+/// `keccak256(hash ++ index)` with the index as 4 big endian bytes.
+pub fn prev_randao(hash: H256, index: u32) -> H256 {
+    let mut data = hash.as_bytes().to_vec();
+    data.extend_from_slice(&index.to_be_bytes());
+    H256::from(keccak256(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ethers::utils::hex;
+
+    use super::*;
+
+    const BASE_FEE: u64 = 2;
+
+    fn deposit(from: u8, gas_limit: u64, is_system_tx: bool) -> SignedTransaction {
+        let deposit_tx = DepositTransaction {
+            nonce: U256::zero(),
+            source_hash: H256::repeat_byte(from),
+            from: H160::repeat_byte(from),
+            action: TransactionAction::Call(H160::repeat_byte(0xee)),
+            mint: None,
+            value: U256::zero(),
+            gas_limit: U256::from(gas_limit),
+            is_system_tx,
+            data: vec![],
+        };
+        SignedTransaction::from_deposit_tx(deposit_tx, 1)
+    }
+
+    fn receipt(used_gas: u64) -> Receipt {
+        Receipt {
+            used_gas: U256::from(used_gas),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gas_refunds_split_unused_and_used_gas() {
+        let recipient = H160::repeat_byte(0xfe);
+        let txs = vec![
+            deposit(1, 100000, false),
+            // used it all, nothing to refund
+            deposit(2, 50000, false),
+            // reported above its limit, charged the limit only
+            deposit(3, 30000, false),
+            // system deposits pay nothing
+            deposit(4, 21000, true),
+        ];
+        let receipts = vec![
+            receipt(40000),
+            receipt(50000),
+            receipt(60000),
+            receipt(21000),
+        ];
+        let base_fee = U256::from(BASE_FEE);
+
+        let credits = gas_refunds(&txs, &receipts, base_fee, Some(recipient));
+        assert_eq!(credits.len(), 2);
+        assert_eq!(credits[0].to, H160::repeat_byte(1));
+        assert_eq!(credits[0].value, U256::from(60000 * BASE_FEE));
+        assert_eq!(credits[1].to, recipient);
+        assert_eq!(
+            credits[1].value,
+            U256::from((40000 + 50000 + 30000) * BASE_FEE)
+        );
+        assert_ne!(credits[0].source_hash, credits[1].source_hash);
+
+        // the used gas is not credited without a fee recipient
+        let credits = gas_refunds(&txs, &receipts, base_fee, None);
+        assert_eq!(credits.len(), 1);
+        assert_eq!(credits[0].value, U256::from(60000 * BASE_FEE));
+
+        assert!(gas_refunds(&[], &[], base_fee, Some(recipient)).is_empty());
+    }
+
+    #[test]
+    fn excluded_credit_returns_the_reserved_fee() {
+        let recipient = H160::repeat_byte(0xfe);
+        let tx = deposit(1, 100000, false);
+        let base_fee = U256::from(BASE_FEE);
+        assert_eq!(reserved_fee(&tx, base_fee), U256::from(100000 * BASE_FEE));
+
+        let credit = excluded_credit(&tx, recipient, base_fee);
+        assert_eq!(credit.to, recipient);
+        assert_eq!(credit.value, reserved_fee(&tx, base_fee));
+        // a refund of the same deposit is another system deposit
+        let refund = &gas_refunds(&[tx], &[receipt(21000)], base_fee, None)[0];
+        assert_ne!(credit.source_hash, refund.source_hash);
+    }
+
+    #[test]
+    fn fee_credit_source_hash() {
+        let recipient = H160::repeat_byte(0xfe);
+        let credit = fee_credit(100, recipient, U256::from(7));
+        assert_eq!(
+            credit.source_hash,
+            H256::from_slice(
+                &hex::decode("f17925951a66072ed69136e40eb230ff28beb0fc8acfcb8daa6d3ef81f07547a")
+                    .unwrap()
+            )
+        );
+        assert_eq!(credit.to, recipient);
+        assert_eq!(credit.value, U256::from(7));
+        assert_ne!(
+            fee_credit(101, recipient, U256::from(7)).source_hash,
+            credit.source_hash
+        );
+    }
+
+    #[test]
+    fn credit_source_hashes_are_unique() {
+        let recipient = H160::repeat_byte(0xfe);
+        let base_fee = U256::from(BASE_FEE);
+        let txs = vec![deposit(1, 100000, false), deposit(2, 100000, false)];
+        let receipts = vec![receipt(40000), receipt(40000)];
+
+        let mut credits = gas_refunds(&txs, &receipts, base_fee, Some(recipient));
+        credits.extend(
+            txs.iter()
+                .map(|tx| excluded_credit(tx, recipient, base_fee)),
+        );
+        credits.push(fee_credit(100, recipient, U256::one()));
+        credits.push(fee_credit(101, recipient, U256::one()));
+        credits.push(mint_credit(H256::repeat_byte(1), recipient, U256::one()));
+        credits.push(mint_credit(H256::repeat_byte(2), recipient, U256::one()));
+        // the used gas credit of other deposits
+        credits.push(gas_refunds(&txs[..1], &receipts[..1], base_fee, Some(recipient))[1].clone());
+
+        let hashes = credits
+            .iter()
+            .map(|credit| credit.source_hash)
+            .collect::<HashSet<_>>();
+        assert_eq!(hashes.len(), credits.len());
+    }
+
+    #[test]
+    fn prev_randao_per_evm_block() {
+        let hash = H256::repeat_byte(0x11);
+        let expected = [
+            "6cfed8e1838d70485a5b0ed8622988d4355fb5388b6c8c3c6109a44167c137b0",
+            "ef8c84a9ad8ecdda9e031ceac2191d129ce83252ce1048241aac92312f3378dd",
+        ];
+        for (index, expected) in expected.iter().enumerate() {
+            assert_eq!(
+                prev_randao(hash, index as u32),
+                H256::from_slice(&hex::decode(expected).unwrap())
+            );
+        }
+        // other BTC blocks give others
+        assert_ne!(
+            prev_randao(H256::repeat_byte(0x12), 0),
+            prev_randao(hash, 0)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use bitcoin::Script;
+use ethers::types::Bytes;
+use rt_evm::model::types::{Receipt, H160, H256, U256};
+use serde::{Deserialize, Serialize};
+use utils::write_atomic;
+
+use crate::{bridge_address, withdrawal_topic};
+
+/// Outputs below this are not standard, such withdrawals stay burned.
+pub const DUST_LIMIT: u64 = 546;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalStatus {
+    Pending,
+    Dust,
+    InvalidScript,
+    /// Sent in the payout `txid`, rebroadcast from `raw` until it confirms.
+    /// It spends `inputs` at `fee_rate` sat/vB, and is replaced with a higher
+    /// fee when still unconfirmed a while after `height`. The payouts it
+    /// `replaced` may confirm instead.
+    Broadcast {
+        txid: String,
+        raw: String,
+        #[serde(default)]
+        height: u64,
+        #[serde(default)]
+        fee_rate: u64,
+        #[serde(default)]
+        inputs: Vec<PayoutInput>,
+        #[serde(default)]
+        replaced: Vec<String>,
+    },
+    Paid(String),
+}
+
+/// Output spent by a payout, with its value in satoshi.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PayoutInput {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Withdrawal {
+    pub tx_hash: H256,
+    pub log_index: u32,
+    pub btc_height: u64,
+    pub from: H160,
+    pub value: U256,
+    pub sat: u64,
+    pub script: Bytes,
+    pub status: WithdrawalStatus,
+}
+
+impl Withdrawal {
+    pub fn id(&self) -> String {
+        format!("{:?}:{}", self.tx_hash, self.log_index)
+    }
+}
+
+fn is_standard(script: &Script) -> bool {
+    script.is_p2pkh()
+        || script.is_p2sh()
+        || script.is_p2wpkh()
+        || script.is_p2wsh()
+        || script.is_p2tr()
+}
+
+/// Withdrawals seen in produced blocks, keyed by `tx_hash:log_index`.
+pub struct WithdrawalStore {
+    path: PathBuf,
+    withdrawals: BTreeMap<String, Withdrawal>,
+}
+
+impl WithdrawalStore {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let withdrawals = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, withdrawals })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string_pretty(&self.withdrawals)?)
+    }
+
+    /// Records the withdrawals logged by the bridge in a block anchored at
+    /// `btc_height`, converted to satoshi with `sat2wei`.
+    pub fn collect(&mut self, btc_height: u64, sat2wei: u64, receipts: &[Receipt]) -> Result<()> {
+        if sat2wei == 0 {
+            return Err(anyhow!("sat2wei is zero"));
+        }
+        let bridge = bridge_address();
+        let topic = withdrawal_topic();
+
+        let mut changed = false;
+        for receipt in receipts.iter() {
+            for (index, log) in receipt.logs.iter().enumerate() {
+                if log.address != bridge || log.topics.len() != 3 || log.topics[0] != topic {
+                    continue;
+                }
+                // collected again when the node restarts after the block
+                if self
+                    .withdrawals
+                    .contains_key(&format!("{:?}:{}", receipt.tx_hash, index))
+                {
+                    continue;
+                }
+                let value = U256::from_big_endian(log.topics[2].as_bytes());
+                let sat = (value / U256::from(sat2wei))
+                    .min(U256::from(u64::MAX))
+                    .as_u64();
+                let withdrawal = Withdrawal {
+                    tx_hash: receipt.tx_hash,
+                    log_index: index as u32,
+                    btc_height,
+                    from: H160::from(log.topics[1]),
+                    value,
+                    sat,
+                    script: log.data.clone().into(),
+                    status: if !is_standard(Script::from_bytes(&log.data)) {
+                        WithdrawalStatus::InvalidScript
+                    } else if sat < DUST_LIMIT {
+                        WithdrawalStatus::Dust
+                    } else {
+                        WithdrawalStatus::Pending
+                    },
+                };
+                log::info!("withdrawal:{:?}", withdrawal);
+                self.withdrawals.insert(withdrawal.id(), withdrawal);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Pending withdrawals with at least `confirmations` BTC blocks on top of
+    /// their anchor at `tip`.
+    pub fn finalized(&self, tip: u64, confirmations: u64) -> Vec<Withdrawal> {
+        self.withdrawals
+            .values()
+            .filter(|w| w.status == WithdrawalStatus::Pending)
+            .filter(|w| w.btc_height + confirmations <= tip)
+            .cloned()
+            .collect()
+    }
+
+    /// Withdrawals whose payout is not confirmed yet.
+    pub fn broadcast(&self) -> Vec<Withdrawal> {
+        self.withdrawals
+            .values()
+            .filter(|w| matches!(w.status, WithdrawalStatus::Broadcast { .. }))
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_status(&mut self, ids: &[String], status: WithdrawalStatus) -> Result<()> {
+        for id in ids.iter() {
+            if let Some(w) = self.withdrawals.get_mut(id) {
+                w.status = status.clone();
+            }
+        }
+        self.save()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Withdrawal> {
+        self.withdrawals.get(id)
+    }
+
+    pub fn by_address(&self, from: H160) -> Vec<&Withdrawal> {
+        self.withdrawals
+            .values()
+            .filter(|w| w.from == from)
+            .collect()
+    }
+}
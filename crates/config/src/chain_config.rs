@@ -1,9 +1,13 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, env, fs, sync::OnceLock};
 
-use ethers::types::{Bytes, H160, H256, U256};
+use anyhow::{anyhow, Result};
+use ethers::{
+    types::{Bytes, H160, H256, U256},
+    utils::keccak256,
+};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Account {
     pub balance: Option<U256>,
     pub nonce: Option<U256>,
@@ -11,9 +15,324 @@ pub struct Account {
     pub storage: Option<BTreeMap<U256, U256>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChainConfig {
     pub chain_id: u32,
+    /// BTC height from which this config applies, see `ChainConfigSchedule::insert`.
+    #[serde(default)]
+    pub activation_height: u64,
+    /// Version byte envelopes must carry while this config is active.
+    #[serde(default)]
+    pub envelope_version: u8,
+    /// Consensus rules of the EVM blocks, configs without one follow those
+    /// of the releases before `PROTOCOL_VERSION` 1.
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// keccak256 of the indexer release binary expected to follow this config,
+    /// a zero hash disables the check.
     pub bin_hash: H256,
+    /// Gas limit of an EVM block.
+    #[serde(default = "default_block_gas_limit")]
+    pub block_gas_limit: u64,
+    /// Gas price in wei deposits are charged through their BTC fee.
+    #[serde(default = "default_base_fee")]
+    pub base_fee: U256,
+    /// Fee recipient of the produced EVM blocks.
+    #[serde(default)]
+    pub coinbase: H160,
+    /// Wei per satoshi.
+    #[serde(default = "default_sat2wei")]
+    pub sat2wei: u64,
+    /// Minimum BTC fee in satoshi an envelope must pay.
+    #[serde(default)]
+    pub min_fee: u64,
+    /// BTC address deposits lock their minted value to, nothing is minted
+    /// without one.
+    #[serde(default)]
+    pub bridge_address: Option<String>,
+    /// BTC address every deposit must pay `da_fee` satoshi to, no DA fee is
+    /// charged without one.
+    #[serde(default)]
+    pub da_fee_address: Option<String>,
+    #[serde(default)]
+    pub da_fee: u64,
+    /// EVM account credited with the BTC fees of each block, they are not
+    /// credited without one.
+    #[serde(default)]
+    pub fee_recipient: Option<H160>,
+    /// Produce no EVM block for BTC blocks without envelopes, instead of one
+    /// EVM block per BTC block.
+    #[serde(default)]
+    pub skip_empty_blocks: bool,
+    /// Execute deposits by decreasing BTC fee per gas, ties by BTC txid and
+    /// vout, instead of in BTC block order.
+    #[serde(default)]
+    pub fee_priority: bool,
+    /// EVM address of the BTC key allowed to publish later configs, derived
+    /// like the deposit senders. The genesis config makes its sender admin
+    /// when unset, a later config without one keeps the current admin.
+    #[serde(default)]
+    pub admin: Option<H160>,
     pub accounts: BTreeMap<H160, Account>,
 }
+
+/// Latest consensus rules. Version 1 starts the EVM blocks with the
+/// predeploys, the L1 attributes and the credit deposits, times them with the
+/// BTC median time past, sets their prevrandao and seeds the genesis balances.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const DEFAULT_BLOCK_GAS_LIMIT: u64 = 30000000;
+pub const DEFAULT_SAT2WEI: u64 = 10000000000;
+
+fn default_block_gas_limit() -> u64 {
+    DEFAULT_BLOCK_GAS_LIMIT
+}
+
+fn default_base_fee() -> U256 {
+    U256::one()
+}
+
+fn default_sat2wei() -> u64 {
+    DEFAULT_SAT2WEI
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            chain_id: 0,
+            activation_height: 0,
+            envelope_version: 0,
+            protocol_version: 0,
+            bin_hash: H256::zero(),
+            block_gas_limit: default_block_gas_limit(),
+            base_fee: default_base_fee(),
+            coinbase: H160::zero(),
+            sat2wei: default_sat2wei(),
+            min_fee: 0,
+            bridge_address: None,
+            da_fee_address: None,
+            da_fee: 0,
+            fee_recipient: None,
+            skip_empty_blocks: false,
+            fee_priority: false,
+            admin: None,
+            accounts: BTreeMap::new(),
+        }
+    }
+}
+
+impl ChainConfig {
+    /// BTC fee in satoshi required to pay for `gas_limit`, rounded up so it
+    /// covers the gas fee the deposit reserves.
+    pub fn required_fee(&self, gas_limit: U256) -> U256 {
+        let sat2wei = U256::from(self.sat2wei);
+        let fee = gas_limit
+            .saturating_mul(self.base_fee)
+            .saturating_add(sat2wei - 1)
+            / sat2wei;
+        fee.max(U256::from(self.min_fee))
+    }
+
+    /// Refuses values the node can not run with, zero `sat2wei` or block gas
+    /// limit, a protocol version it does not know or credits the protocol
+    /// version has no system deposits for.
+    pub fn validate(&self) -> Result<()> {
+        if self.sat2wei == 0 {
+            return Err(anyhow!("sat2wei is zero"));
+        }
+        if self.block_gas_limit == 0 {
+            return Err(anyhow!("block gas limit is zero"));
+        }
+        if self.protocol_version > PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "protocol version {} above {}",
+                self.protocol_version,
+                PROTOCOL_VERSION
+            ));
+        }
+        if !self.protocol_v1() && (self.bridge_address.is_some() || self.fee_recipient.is_some()) {
+            return Err(anyhow!(
+                "bridge address and fee recipient need protocol version 1"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the rules of protocol version 1 apply, see `PROTOCOL_VERSION`.
+    pub fn protocol_v1(&self) -> bool {
+        self.protocol_version >= 1
+    }
+
+    pub fn check_bin_hash(&self, height: u64) -> Result<()> {
+        if self.bin_hash.is_zero() {
+            return Ok(());
+        }
+
+        let running = current_bin_hash()?;
+        if running != self.bin_hash {
+            Err(anyhow!(
+                "upgrade required at BTC height {}: expected bin hash {:?}, running {:?}",
+                height,
+                self.bin_hash,
+                running
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn current_bin_hash() -> Result<H256> {
+    static BIN_HASH: OnceLock<H256> = OnceLock::new();
+
+    if let Some(hash) = BIN_HASH.get() {
+        return Ok(*hash);
+    }
+    let bin = fs::read(env::current_exe()?)?;
+    Ok(*BIN_HASH.get_or_init(|| H256::from(keccak256(bin))))
+}
+
+/// Chain configs keyed by the BTC height they take effect at.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ChainConfigSchedule {
+    configs: BTreeMap<u64, ChainConfig>,
+}
+
+impl ChainConfigSchedule {
+    pub fn new(genesis_height: u64, mut cfg: ChainConfig) -> Self {
+        cfg.activation_height = genesis_height;
+        let mut configs = BTreeMap::new();
+        configs.insert(genesis_height, cfg);
+        Self { configs }
+    }
+
+    /// Schedules a config published in the block at `height`. It never affects
+    /// the block it was published in: it applies from
+    /// `max(activation_height, height + 1)`, which is returned. A later
+    /// config for the same height replaces the earlier one.
+    pub fn insert(&mut self, height: u64, mut cfg: ChainConfig) -> u64 {
+        let activation_height = cfg.activation_height.max(height + 1);
+        cfg.activation_height = activation_height;
+        self.configs.insert(activation_height, cfg);
+        activation_height
+    }
+
+    pub fn active(&self, height: u64) -> Option<&ChainConfig> {
+        self.configs
+            .range(..=height)
+            .next_back()
+            .map(|(_, cfg)| cfg)
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &ChainConfig> {
+        self.configs.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(chain_id: u32, activation_height: u64) -> ChainConfig {
+        ChainConfig {
+            chain_id,
+            activation_height,
+            ..Default::default()
+        }
+    }
+
+    fn active_id(schedule: &ChainConfigSchedule, height: u64) -> Option<u32> {
+        schedule.active(height).map(|cfg| cfg.chain_id)
+    }
+
+    #[test]
+    fn schedule_genesis() {
+        let schedule = ChainConfigSchedule::new(100, cfg(1, 7));
+        assert_eq!(schedule.active(100).unwrap().activation_height, 100);
+        assert_eq!(active_id(&schedule, 99), None);
+        assert_eq!(active_id(&schedule, 100), Some(1));
+        assert_eq!(active_id(&schedule, u64::MAX), Some(1));
+        assert!(!schedule.is_empty());
+        assert!(ChainConfigSchedule::default().is_empty());
+    }
+
+    #[test]
+    fn schedule_insert_after_publishing_block() {
+        let mut schedule = ChainConfigSchedule::new(100, cfg(1, 0));
+
+        // no activation height and one in the past both apply from the next block
+        assert_eq!(schedule.insert(110, cfg(2, 0)), 111);
+        assert_eq!(schedule.insert(120, cfg(3, 105)), 121);
+        assert_eq!(active_id(&schedule, 110), Some(1));
+        assert_eq!(active_id(&schedule, 111), Some(2));
+        assert_eq!(active_id(&schedule, 120), Some(2));
+        assert_eq!(active_id(&schedule, 121), Some(3));
+        assert_eq!(schedule.active(121).unwrap().activation_height, 121);
+    }
+
+    #[test]
+    fn schedule_insert_future_activation() {
+        let mut schedule = ChainConfigSchedule::new(100, cfg(1, 0));
+
+        assert_eq!(schedule.insert(110, cfg(2, 200)), 200);
+        assert_eq!(active_id(&schedule, 199), Some(1));
+        assert_eq!(active_id(&schedule, 200), Some(2));
+
+        // a config published later but activating earlier applies in between
+        assert_eq!(schedule.insert(120, cfg(3, 150)), 150);
+        assert_eq!(active_id(&schedule, 149), Some(1));
+        assert_eq!(active_id(&schedule, 150), Some(3));
+        assert_eq!(active_id(&schedule, 200), Some(2));
+
+        let history = schedule
+            .history()
+            .map(|cfg| cfg.chain_id)
+            .collect::<Vec<_>>();
+        assert_eq!(history, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn schedule_insert_replaces_same_height() {
+        let mut schedule = ChainConfigSchedule::new(100, cfg(1, 0));
+
+        assert_eq!(schedule.insert(110, cfg(2, 0)), 111);
+        assert_eq!(schedule.insert(110, cfg(3, 0)), 111);
+        assert_eq!(active_id(&schedule, 111), Some(3));
+        assert_eq!(schedule.history().count(), 2);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(ChainConfig::default().validate().is_ok());
+        let zero_sat2wei = ChainConfig {
+            sat2wei: 0,
+            ..Default::default()
+        };
+        assert!(zero_sat2wei.validate().is_err());
+        let zero_gas_limit = ChainConfig {
+            block_gas_limit: 0,
+            ..Default::default()
+        };
+        assert!(zero_gas_limit.validate().is_err());
+        let unknown_version = ChainConfig {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Default::default()
+        };
+        assert!(unknown_version.validate().is_err());
+
+        // credits are system deposits, the legacy rules have none
+        let legacy_credits = ChainConfig {
+            fee_recipient: Some(H160::repeat_byte(1)),
+            ..Default::default()
+        };
+        assert!(legacy_credits.validate().is_err());
+        let credits = ChainConfig {
+            protocol_version: PROTOCOL_VERSION,
+            ..legacy_credits
+        };
+        assert!(credits.validate().is_ok());
+    }
+}
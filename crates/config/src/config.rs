@@ -25,6 +25,44 @@ pub struct BtcConfig {
     pub fee_address: String,
 }
 
+/// Operator side of the peg-out, pays finalized withdrawals from the
+/// p2wpkh address of `operator_sk`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WithdrawalConfig {
+    pub operator_sk: String,
+    pub confirmations: u64,
+    /// Fee rate in sat/vB the payouts pay at least.
+    #[serde(default = "default_fee_rate")]
+    pub fee_rate: u64,
+    /// Withdrawals paid in one payout at most.
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+    /// BTC blocks a payout may stay unconfirmed before it is replaced with a
+    /// higher fee, zero never replaces it.
+    #[serde(default = "default_bump_after")]
+    pub bump_after: u64,
+}
+
+fn default_fee_rate() -> u64 {
+    1
+}
+
+fn default_max_batch() -> usize {
+    100
+}
+
+fn default_bump_after() -> u64 {
+    6
+}
+
+/// Pins the envelope carrying the genesis config, instead of taking the first
+/// one found from `--start`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GenesisConfig {
+    pub block_hash: String,
+    pub txid: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub default_da: DaType,
@@ -40,6 +78,8 @@ pub struct Config {
     #[cfg(feature = "ethereum")]
     pub ethereum: Option<EthereumConfig>,
     pub btc: BtcConfig,
+    pub withdrawal: Option<WithdrawalConfig>,
+    pub genesis: Option<GenesisConfig>,
 }
 
 impl Config {
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use ethers::{
+    types::{BigEndianHash, H256, U256},
+    utils::{ChainConfig as GethChainConfig, Genesis, GenesisAccount},
+};
+
+use crate::{Account, ChainConfig, PROTOCOL_VERSION};
+
+impl ChainConfig {
+    /// Builds a chain config from a geth `genesis.json`, taking `config.chainId`,
+    /// `alloc`, `gasLimit`, `baseFeePerGas` and `coinbase`. The bin hash is left
+    /// zero.
+    pub fn from_genesis(genesis: Genesis) -> Result<Self> {
+        let chain_id = u32::try_from(genesis.config.chain_id)
+            .map_err(|_| anyhow!("chain id too large:{}", genesis.config.chain_id))?;
+
+        let accounts = genesis
+            .alloc
+            .into_iter()
+            .map(|(address, account)| {
+                let account = Account {
+                    balance: Some(account.balance),
+                    nonce: account.nonce.map(U256::from),
+                    code: account.code,
+                    storage: account.storage.map(|storage| {
+                        storage
+                            .into_iter()
+                            .map(|(k, v)| (k.into_uint(), v.into_uint()))
+                            .collect()
+                    }),
+                };
+                (address, account)
+            })
+            .collect();
+
+        // the accounts are seeded from protocol version 1 on
+        let mut cfg = Self {
+            chain_id,
+            protocol_version: PROTOCOL_VERSION,
+            coinbase: genesis.coinbase,
+            accounts,
+            ..Default::default()
+        };
+        if !genesis.gas_limit.is_zero() {
+            cfg.block_gas_limit = genesis.gas_limit.as_u64();
+        }
+        if let Some(base_fee) = genesis.base_fee_per_gas {
+            cfg.base_fee = base_fee;
+        }
+        Ok(cfg)
+    }
+
+    /// Builds a geth `genesis.json` from the chain config, the reverse of
+    /// `from_genesis`. Nonces above `u64::MAX` are refused.
+    pub fn to_genesis(&self) -> Result<Genesis> {
+        let alloc = self
+            .accounts
+            .iter()
+            .map(|(address, account)| {
+                let nonce = account
+                    .nonce
+                    .map(|nonce| {
+                        u64::try_from(nonce)
+                            .map_err(|_| anyhow!("nonce of {:?} too large:{}", address, nonce))
+                    })
+                    .transpose()?;
+                let account = GenesisAccount {
+                    nonce,
+                    balance: account.balance.unwrap_or_default(),
+                    code: account.code.clone(),
+                    storage: account.storage.as_ref().map(|storage| {
+                        storage
+                            .iter()
+                            .map(|(k, v)| (H256::from_uint(k), H256::from_uint(v)))
+                            .collect()
+                    }),
+                };
+                Ok((*address, account))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Genesis {
+            config: GethChainConfig {
+                chain_id: self.chain_id.into(),
+                ..Default::default()
+            },
+            alloc,
+            gas_limit: self.block_gas_limit.into(),
+            base_fee_per_gas: Some(self.base_fee),
+            coinbase: self.coinbase,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ethers::types::{Bytes, H160};
+
+    use super::*;
+
+    fn accounts() -> BTreeMap<H160, Account> {
+        let mut accounts = BTreeMap::new();
+        accounts.insert(
+            H160::repeat_byte(1),
+            Account {
+                balance: Some(U256::from(10).pow(U256::from(20))),
+                nonce: None,
+                code: None,
+                storage: None,
+            },
+        );
+        accounts.insert(
+            H160::repeat_byte(2),
+            Account {
+                balance: Some(U256::zero()),
+                nonce: Some(U256::one()),
+                code: Some(Bytes::from(vec![0x60, 0x00, 0x60, 0x00, 0xf3])),
+                storage: Some(BTreeMap::from([
+                    (U256::zero(), U256::from(7)),
+                    (U256::MAX, U256::one()),
+                ])),
+            },
+        );
+        accounts
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let cfg = ChainConfig {
+            chain_id: 42,
+            block_gas_limit: 20000000,
+            base_fee: U256::from(7),
+            coinbase: H160::repeat_byte(3),
+            accounts: accounts(),
+            ..Default::default()
+        };
+        let back = ChainConfig::from_genesis(cfg.to_genesis().unwrap()).unwrap();
+
+        assert_eq!(back.chain_id, cfg.chain_id);
+        assert_eq!(back.block_gas_limit, cfg.block_gas_limit);
+        assert_eq!(back.base_fee, cfg.base_fee);
+        assert_eq!(back.coinbase, cfg.coinbase);
+        assert_eq!(back.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(back.to_genesis().unwrap(), cfg.to_genesis().unwrap());
+    }
+
+    #[test]
+    fn genesis_round_trip() {
+        let genesis = ChainConfig {
+            chain_id: 42,
+            accounts: accounts(),
+            ..Default::default()
+        }
+        .to_genesis()
+        .unwrap();
+        let back = ChainConfig::from_genesis(genesis.clone())
+            .unwrap()
+            .to_genesis()
+            .unwrap();
+        assert_eq!(back, genesis);
+    }
+
+    #[test]
+    fn to_genesis_refuses_large_nonce() {
+        let mut accounts = accounts();
+        accounts.get_mut(&H160::repeat_byte(2)).unwrap().nonce = Some(U256::from(u64::MAX) + 1);
+        let cfg = ChainConfig {
+            accounts,
+            ..Default::default()
+        };
+        assert!(cfg.to_genesis().is_err());
+    }
+}
@@ -5,3 +5,5 @@ pub use config::*;
 
 mod chain_config;
 pub use chain_config::*;
+
+mod genesis;
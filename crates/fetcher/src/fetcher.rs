@@ -1,28 +1,123 @@
-use std::sync::Arc;
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::{anyhow, Result};
 use bitcoin::{
+    block::Header,
     hashes::Hash,
-    opcodes::all::{OP_PUSHBYTES_40, OP_RETURN},
-    Block, Transaction, TxOut, Txid,
+    opcodes::all::{OP_PUSHBYTES_40, OP_PUSHBYTES_44, OP_RETURN},
+    Address, Block, BlockHash, ScriptBuf, Transaction, TxOut, Txid,
 };
 use bitcoincore_rpc::{Client, RpcApi};
-use config::ChainConfig;
+use config::{ChainConfig, ChainConfigSchedule};
 use da::DAServiceManager;
 use ethers::utils::rlp::Rlp;
 use rt_evm::model::types::{DepositTransaction, SignedTransaction, H160, H256, U256};
-use tx_builder::{btc::BtcTransactionBuilder, SAT2WEI};
+use tx_builder::btc::BtcTransactionBuilder;
 use utils::ScriptCode;
 
+/// An envelope that decoded but was not accepted.
+#[derive(Debug)]
+pub struct Rejection {
+    pub btc_txid: H256,
+    pub vout: u32,
+    pub reason: String,
+}
+
 pub enum Data {
     Config(ChainConfig),
     Transaction(Box<SignedTransaction>),
+    Rejected(Rejection),
+    /// BTC fee in wei of a transaction with accepted deposits.
+    Fee(U256),
+    /// Wei a transaction bridged, credited to its sender whether its deposits
+    /// are accepted or not.
+    Mint {
+        btc_txid: H256,
+        to: H160,
+        value: U256,
+    },
+}
+
+/// An invalid address in the chain config disables the rule using it.
+fn address_script(addr: Option<&String>) -> Option<ScriptBuf> {
+    let addr = addr?;
+    match Address::from_str(addr) {
+        Ok(addr) => Some(addr.assume_checked().script_pubkey()),
+        Err(e) => {
+            log::error!("chain config address {} error:{}", addr, e);
+            None
+        }
+    }
+}
+
+/// Satoshi a BTC transaction pays to `script`.
+fn paid_to(btc_tx: &Transaction, script: Option<&ScriptBuf>) -> u64 {
+    match script {
+        Some(script) => btc_tx
+            .output
+            .iter()
+            .filter(|txout| &txout.script_pubkey == script)
+            .map(|txout| txout.value.to_sat())
+            .sum(),
+        None => 0,
+    }
+}
+
+/// BTC fee in wei and gas limit of the accepted deposits of a transaction.
+fn fee_and_gas(datas: &[Data]) -> (U256, U256) {
+    datas.iter().fold(
+        (U256::zero(), U256::zero()),
+        |(fee, gas), data| match data {
+            Data::Fee(v) => (fee + v, gas),
+            Data::Transaction(tx) => (fee, gas + tx.transaction.unsigned.gas_limit()),
+            _ => (fee, gas),
+        },
+    )
+}
+
+/// Orders the data decoded from the transactions of a block for
+/// `fee_priority`: the deposits go last, by decreasing BTC fee per gas of
+/// their transaction and ties by BTC txid, the rest keeps the block order.
+fn fee_priority_order(decoded: Vec<(Txid, Vec<Data>)>) -> Vec<Data> {
+    let mut ret = vec![];
+    let mut deposits = vec![];
+    for (txid, datas) in decoded {
+        let (fee, gas) = fee_and_gas(&datas);
+        let rate = fee / gas.max(U256::one());
+        for data in datas {
+            match data {
+                Data::Transaction(_) => deposits.push((rate, txid, data)),
+                _ => ret.push(data),
+            }
+        }
+    }
+    // stable, deposits of one transaction stay in vout order
+    deposits.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    ret.extend(deposits.into_iter().map(|(_, _, data)| data));
+    ret
+}
+
+/// A fetched BTC block and the data decoded from it.
+pub struct BtcBlock {
+    pub height: u64,
+    pub hash: H256,
+    pub time: u64,
+    /// Median fee rate of the block in sat/vB.
+    pub median_fee_rate: u64,
+    pub header: Header,
+    pub datas: Vec<Data>,
 }
 
 pub struct Fetcher {
     height: u64,
     builder: BtcTransactionBuilder,
-    pub chain_id: u32,
+    schedule: Arc<RwLock<ChainConfigSchedule>>,
+    chain_cfg: ChainConfig,
+    bridge_script: Option<ScriptBuf>,
+    da_fee_script: Option<ScriptBuf>,
     da_mgr: Arc<DAServiceManager>,
     client: Arc<Client>,
 }
@@ -33,7 +128,7 @@ impl Fetcher {
         da_mgr: Arc<DAServiceManager>,
         electrs_url: &str,
         start: u64,
-        chain_id: u32,
+        schedule: Arc<RwLock<ChainConfigSchedule>>,
     ) -> Result<Self> {
         let block_cnt = client.clone().get_block_count()?;
         if start > block_cnt + 1 {
@@ -45,39 +140,135 @@ impl Fetcher {
         Ok(Self {
             height: start,
             builder: BtcTransactionBuilder::new(electrs_url, client.clone())?,
-            chain_id,
+            schedule,
+            chain_cfg: ChainConfig::default(),
+            bridge_script: None,
+            da_fee_script: None,
             da_mgr,
             client,
         })
     }
 
-    pub async fn fetcher_first_cfg(&mut self) -> Result<(u64, ChainConfig)> {
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Finds the first config envelope from the start height, returns the
+    /// height after its block and its transaction.
+    pub async fn fetcher_first_cfg(&mut self) -> Result<(u64, Txid, ChainConfig)> {
         loop {
-            if let Some((_, datas)) = self.fetcher().await? {
-                for data in datas {
-                    if let Data::Config(cfg) = data {
-                        return Ok((self.height, cfg));
-                    }
+            if let Some(block) = self.get_block().await? {
+                let found = self.genesis_cfg(&block, None).await?;
+                self.height += 1;
+                if let Some((txid, cfg)) = found {
+                    return Ok((self.height, txid, cfg));
                 }
             }
         }
     }
 
-    pub async fn fetcher(&mut self) -> Result<Option<(u64, Vec<Data>)>> {
+    /// Decodes the genesis config of the block `block_hash`, which must be on
+    /// the active chain, from the envelope `txid` or the first one found.
+    pub async fn fetcher_genesis_cfg(
+        &mut self,
+        block_hash: &BlockHash,
+        txid: Option<&Txid>,
+    ) -> Result<(u64, Txid, ChainConfig)> {
+        let info = self.client.get_block_header_info(block_hash)?;
+        if info.confirmations < 0 {
+            return Err(anyhow!(
+                "genesis block {} not on the active chain",
+                block_hash
+            ));
+        }
+        let block = self.client.get_block(block_hash)?;
+        if let Some(txid) = txid.filter(|txid| !block.txdata.iter().any(|tx| tx.txid() == **txid)) {
+            return Err(anyhow!("genesis tx {} not in block {}", txid, block_hash));
+        }
+
+        self.height = info.height as u64;
+        let (txid, cfg) = self
+            .genesis_cfg(&block, txid)
+            .await?
+            .ok_or(anyhow!("genesis block {} carries no config", block_hash))?;
+        self.height += 1;
+        Ok((self.height, txid, cfg))
+    }
+
+    /// First config envelope of `block`, of the transaction `txid` if given.
+    async fn genesis_cfg(
+        &self,
+        block: &Block,
+        txid: Option<&Txid>,
+    ) -> Result<Option<(Txid, ChainConfig)>> {
+        for tx in block.txdata.iter() {
+            if txid.is_some_and(|txid| tx.txid() != *txid) {
+                continue;
+            }
+            let cfg = self
+                .decode_data(tx)
+                .await?
+                .unwrap_or_default()
+                .into_iter()
+                .find_map(|data| match data {
+                    Data::Config(cfg) => Some(cfg),
+                    _ => None,
+                });
+            if let Some(cfg) = cfg {
+                return Ok(Some((tx.txid(), cfg)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn fetcher(&mut self) -> Result<Option<BtcBlock>> {
         let block = if let Some(block) = self.get_block().await? {
             block
         } else {
             return Ok(None);
         };
-        self.height += 1;
+        // decode with the rules active at this height, configs published in
+        // this block only apply from a later one
+        if let Some(cfg) = self
+            .schedule
+            .read()
+            .map_err(|e| anyhow!("{}", e))?
+            .active(self.height)
+        {
+            if cfg.activation_height != self.chain_cfg.activation_height {
+                self.chain_cfg = cfg.clone();
+                self.bridge_script = address_script(self.chain_cfg.bridge_address.as_ref());
+                self.da_fee_script = address_script(self.chain_cfg.da_fee_address.as_ref());
+            }
+        }
+        let height = self.height;
+        let median_fee_rate = self
+            .client
+            .get_block_stats(height)?
+            .fee_rate_percentiles
+            .fr_50th
+            .to_sat();
 
-        let mut ret = vec![];
+        let mut decoded = vec![];
         for tx in block.txdata.iter() {
             if let Some(datas) = self.decode_data(tx).await? {
-                ret.extend(datas);
+                decoded.push((tx.txid(), datas));
             };
         }
-        Ok(Some((block.header.time.into(), ret)))
+        let ret = if self.chain_cfg.fee_priority {
+            fee_priority_order(decoded)
+        } else {
+            decoded.into_iter().flat_map(|(_, datas)| datas).collect()
+        };
+        self.height += 1;
+        Ok(Some(BtcBlock {
+            height,
+            hash: H256::from(block.block_hash().to_byte_array()),
+            time: block.header.time.into(),
+            median_fee_rate,
+            header: block.header,
+            datas: ret,
+        }))
     }
 
     async fn get_block(&self) -> Result<Option<Block>> {
@@ -125,6 +316,12 @@ impl Fetcher {
         }
     }
 
+    /// Wei minted by a BTC transaction, from the value it locks to the bridge.
+    fn bridge_amount(&self, btc_tx: &Transaction) -> U256 {
+        let sat = paid_to(btc_tx, self.bridge_script.as_ref());
+        U256::from(sat) * U256::from(self.chain_cfg.sat2wei)
+    }
+
     async fn decode_data(&self, btc_tx: &Transaction) -> Result<Option<Vec<Data>>> {
         let source_hash = H256::from(btc_tx.txid().to_byte_array());
 
@@ -140,36 +337,128 @@ impl Fetcher {
         };
 
         let mut ret = vec![];
-        let fee = U256::from(match self.verify_transaction(btc_tx)? {
-            Some(v) => v,
-            None => return Ok(None),
-        });
+        // a transaction without fee still bridges its value
+        let mut fee = U256::from(self.verify_transaction(btc_tx)?.unwrap_or_default());
 
+        let mint = self.bridge_amount(btc_tx);
+        let total_fee = fee;
+        let mut accepted = false;
+        let mut da_fee = paid_to(btc_tx, self.da_fee_script.as_ref());
         for (index, out) in btc_tx.output.iter().enumerate() {
-            let data = match self.decode_vout(out, source_hash, from).await {
+            let data = match self
+                .decode_vout(out, source_hash, index as u32, from, mint)
+                .await
+            {
                 Ok(data) => data,
                 Err(e) => {
                     log::debug!("decode {} vout {} error:{}", btc_tx.txid(), index, e);
                     continue;
                 }
             };
-            if let Data::Transaction(ref tx) = data {
-                if fee >= (tx.transaction.unsigned.gas_limit() / U256::from(SAT2WEI)) {
-                    ret.push(data)
+            // the fee and DA fee are shared by all envelopes of the transaction in
+            // vout order
+            if let Data::Config(cfg) = data {
+                ret.push(match self.check_config(from, cfg) {
+                    Ok(cfg) => Data::Config(cfg),
+                    Err(e) => Data::Rejected(Rejection {
+                        btc_txid: source_hash,
+                        vout: index as u32,
+                        reason: e.to_string(),
+                    }),
+                });
+            } else if let Data::Transaction(ref tx) = data {
+                let required_fee = self
+                    .chain_cfg
+                    .required_fee(*tx.transaction.unsigned.gas_limit());
+                let required_da_fee = match self.da_fee_script {
+                    Some(_) => self.chain_cfg.da_fee,
+                    None => 0,
+                };
+                let reason = if fee < required_fee {
+                    Some(format!("fee required {}, remaining {}", required_fee, fee))
+                } else if da_fee < required_da_fee {
+                    Some(format!(
+                        "da fee required {}, remaining {}",
+                        required_da_fee, da_fee
+                    ))
+                } else {
+                    None
                 };
+
+                if let Some(reason) = reason {
+                    ret.push(Data::Rejected(Rejection {
+                        btc_txid: source_hash,
+                        vout: index as u32,
+                        reason,
+                    }))
+                } else {
+                    fee -= required_fee;
+                    da_fee -= required_da_fee;
+                    accepted = true;
+                    ret.push(data)
+                }
             } else {
                 ret.push(data)
             }
         }
+        if !mint.is_zero() {
+            ret.push(Data::Mint {
+                btc_txid: source_hash,
+                to: from,
+                value: mint,
+            });
+        }
+        if accepted {
+            ret.push(Data::Fee(total_fee * U256::from(self.chain_cfg.sat2wei)));
+        }
 
         Ok(Some(ret))
     }
 
-    async fn decode_vout(&self, out: &TxOut, _source_hash: H256, sender: H160) -> Result<Data> {
+    /// Configs the node can not run with are refused. Configs after genesis
+    /// are only accepted from the admin of the active config, the genesis
+    /// config has no admin to check against.
+    fn check_config(&self, sender: H160, mut cfg: ChainConfig) -> Result<ChainConfig> {
+        cfg.validate()?;
+        let genesis = self
+            .schedule
+            .read()
+            .map_err(|e| anyhow!("{}", e))?
+            .is_empty();
+        if !genesis && self.chain_cfg.admin != Some(sender) {
+            return Err(anyhow!(
+                "config sender {:?} is not the admin {:?}",
+                sender,
+                self.chain_cfg.admin
+            ));
+        }
+
+        cfg.admin = cfg.admin.or(if genesis {
+            Some(sender)
+        } else {
+            self.chain_cfg.admin
+        });
+        Ok(cfg)
+    }
+
+    /// Decodes the envelope of an output, an output without one is an
+    /// error. Expired envelopes are rejected.
+    async fn decode_vout(
+        &self,
+        out: &TxOut,
+        source_hash: H256,
+        vout: u32,
+        sender: H160,
+        mint: U256,
+    ) -> Result<Data> {
         let code = out.script_pubkey.as_bytes();
-        if code.len() != 42
-            || Some(OP_RETURN) != code.first().cloned().map(From::from)
-            || Some(OP_PUSHBYTES_40) != code.get(1).cloned().map(From::from)
+        let push = match code.len() {
+            42 => OP_PUSHBYTES_40,
+            46 => OP_PUSHBYTES_44,
+            len => return Err(anyhow!("code.len():{}", len)),
+        };
+        if Some(OP_RETURN) != code.first().cloned().map(From::from)
+            || Some(push) != code.get(1).cloned().map(From::from)
         {
             return Err(anyhow!(
                 "code.len():{},first:{:?},last:{:?}",
@@ -180,7 +469,25 @@ impl Fetcher {
         }
         let vc = ScriptCode::decode(&code[2..])?;
         log::debug!("decode_vout:{}:{:?}", hex::encode(code), vc);
-        vc.check(self.chain_id, self.da_mgr.types())?;
+        vc.check(
+            self.chain_cfg.chain_id,
+            self.chain_cfg.envelope_version,
+            self.da_mgr.types(),
+        )?;
+
+        if let Some(valid_until) = vc
+            .valid_until
+            .filter(|valid_until| self.height > *valid_until as u64)
+        {
+            return Ok(Data::Rejected(Rejection {
+                btc_txid: source_hash,
+                vout,
+                reason: format!(
+                    "envelope expired at {}, included at {}",
+                    valid_until, self.height
+                ),
+            }));
+        }
 
         let da_hash = vc.da_hash();
         log::debug!("da hash:{}", hex::encode(&da_hash));
@@ -194,9 +501,18 @@ impl Fetcher {
             }
             let mut deposit_tx = DepositTransaction::decode(&Rlp::new(&tx_data[1..]))?;
             deposit_tx.from = sender;
+            if let Some(v) = deposit_tx.mint.filter(|v| *v != mint) {
+                return Ok(Data::Rejected(Rejection {
+                    btc_txid: source_hash,
+                    vout,
+                    reason: format!("mint mismatch: payload {}, bridged {}", v, mint),
+                }));
+            }
+            // the bridged value is credited by a system deposit before it
+            deposit_tx.mint = None;
             //deposit_tx.source_hash = source_hash;
 
-            let tx = SignedTransaction::from_deposit_tx(deposit_tx, self.chain_id.into());
+            let tx = SignedTransaction::from_deposit_tx(deposit_tx, self.chain_cfg.chain_id.into());
             Ok(Data::Transaction(Box::new(tx)))
         } else if vc.tx_type == 1 {
             let cfg = serde_json::from_slice(&tx_data)?;
@@ -207,3 +523,74 @@ impl Fetcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rt_evm::model::types::{TransactionAction, UnsignedTransaction};
+
+    use super::*;
+
+    fn deposit(id: u8, gas_limit: u64) -> Data {
+        let deposit_tx = DepositTransaction {
+            nonce: U256::zero(),
+            source_hash: H256::repeat_byte(id),
+            from: H160::repeat_byte(id),
+            action: TransactionAction::Call(H160::repeat_byte(0xee)),
+            mint: None,
+            value: U256::zero(),
+            gas_limit: U256::from(gas_limit),
+            is_system_tx: false,
+            data: vec![],
+        };
+        Data::Transaction(Box::new(SignedTransaction::from_deposit_tx(deposit_tx, 1)))
+    }
+
+    fn label(data: &Data) -> String {
+        match data {
+            Data::Transaction(tx) => match &tx.transaction.unsigned {
+                UnsignedTransaction::Deposit(tx) => format!("deposit {}", tx.source_hash[0]),
+                _ => unreachable!(),
+            },
+            Data::Fee(fee) => format!("fee {}", fee),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fee_priority_order_by_rate_then_txid() {
+        let decoded = vec![
+            // 1 wei per gas over its two deposits
+            (
+                Txid::from_byte_array([3; 32]),
+                vec![deposit(1, 100), deposit(2, 200), Data::Fee(U256::from(300))],
+            ),
+            // 3 wei per gas
+            (
+                Txid::from_byte_array([4; 32]),
+                vec![deposit(3, 100), Data::Fee(U256::from(300))],
+            ),
+            // 1 wei per gas too, first by txid
+            (
+                Txid::from_byte_array([2; 32]),
+                vec![deposit(4, 100), Data::Fee(U256::from(100))],
+            ),
+        ];
+
+        let order = fee_priority_order(decoded)
+            .iter()
+            .map(label)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                "fee 300",
+                "fee 300",
+                "fee 100",
+                "deposit 3",
+                "deposit 4",
+                "deposit 1",
+                "deposit 2",
+            ]
+        );
+    }
+}
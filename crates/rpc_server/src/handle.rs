@@ -1,20 +1,32 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use bitcoin::{consensus::deserialize, Address, Amount, Network, Transaction};
+use bitcoin::{
+    consensus::deserialize,
+    opcodes::all::{OP_PUSHBYTES_40, OP_PUSHBYTES_44, OP_RETURN},
+    Address, Amount, Network, Transaction,
+};
 use bitcoincore_rpc::{
-    jsonrpc::serde_json::{json, Value},
+    jsonrpc::serde_json::{self, json, Value},
     Client, RpcApi,
 };
+use bridge::WithdrawalStore;
+use config::ChainConfigSchedule;
 use da::DAServiceManager;
-use ethers::types::Bytes;
+use ethers::types::{Bytes, H160};
 use json_rpc_server::{Handle, RPCError, RPCResult};
 use serde::{Deserialize, Serialize};
+use utils::ScriptCode;
 
 pub struct NovoHandle {
     da_mgr: Arc<DAServiceManager>,
     client: Arc<Client>,
+    schedule: Arc<RwLock<ChainConfigSchedule>>,
+    withdrawals: Arc<RwLock<WithdrawalStore>>,
     fee_address: Address,
     da_fee: Amount,
     network: Network,
@@ -24,6 +36,8 @@ impl NovoHandle {
     pub fn new(
         da_mgr: Arc<DAServiceManager>,
         client: Arc<Client>,
+        schedule: Arc<RwLock<ChainConfigSchedule>>,
+        withdrawals: Arc<RwLock<WithdrawalStore>>,
         da_fee: u64,
         fee_address: &str,
         network: &str,
@@ -34,11 +48,58 @@ impl NovoHandle {
         Ok(Self {
             da_mgr,
             client,
+            schedule,
+            withdrawals,
             fee_address,
             da_fee,
             network,
         })
     }
+
+    /// DA fee rule of the next BTC block: the chain config's when it sets one,
+    /// the local config's otherwise.
+    fn da_info(&self) -> RPCResult<(Address, Amount)> {
+        let height = self
+            .client
+            .get_block_count()
+            .map_err(|e| RPCError::internal_error(format!("get_block_count:{e}")))?
+            + 1;
+        let schedule = self
+            .schedule
+            .read()
+            .map_err(|e| RPCError::internal_error(format!("chain config lock:{e}")))?;
+
+        match schedule
+            .active(height)
+            .and_then(|cfg| Some((cfg.da_fee_address.as_ref()?, cfg.da_fee)))
+        {
+            Some((addr, fee)) => {
+                let addr = Address::from_str(addr)
+                    .map(|addr| addr.assume_checked())
+                    .map_err(|e| RPCError::internal_error(format!("da fee address:{e}")))?;
+                Ok((addr, Amount::from_sat(fee)))
+            }
+            None => Ok((self.fee_address.clone(), self.da_fee)),
+        }
+    }
+}
+
+/// Outputs of `tx` carrying a deposit envelope.
+fn deposit_envelopes(tx: &Transaction) -> u64 {
+    tx.output
+        .iter()
+        .filter(|txout| {
+            let code = txout.script_pubkey.as_bytes();
+            let push = match code.len() {
+                42 => OP_PUSHBYTES_40,
+                46 => OP_PUSHBYTES_44,
+                _ => return false,
+            };
+            code[0] == OP_RETURN.to_u8()
+                && code[1] == push.to_u8()
+                && ScriptCode::decode(&code[2..]).is_ok_and(|code| code.tx_type == 0)
+        })
+        .count() as u64
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,6 +107,7 @@ impl NovoHandle {
 pub enum NovoHandleRequest {
     SendRawTransactionArray((Bytes, Bytes)),
     SendRawTransaction { tx_data: Bytes, btc_tx: Bytes },
+    SingleParam((String,)),
     GetDaINfo,
 }
 
@@ -57,6 +119,13 @@ impl NovoHandleRequest {
             _ => Err(RPCError::invalid_params()),
         }
     }
+
+    pub fn into_single_param(self) -> RPCResult<String> {
+        match self {
+            Self::SingleParam((s,)) => Ok(s),
+            _ => Err(RPCError::invalid_params()),
+        }
+    }
 }
 
 #[async_trait]
@@ -85,17 +154,26 @@ impl Handle for NovoHandle {
                 let tx: Transaction = deserialize(&btc_tx_bytes)
                     .map_err(|e| RPCError::internal_error(format!("deserialize btc tx:{e}")))?;
 
-                let mut flag = false;
-                for txout in tx.output.iter() {
-                    if Ok(self.fee_address.clone())
-                        == Address::from_script(&txout.script_pubkey, self.network)
-                        && txout.value >= self.da_fee
-                    {
-                        flag = true;
-                    };
-                }
-                if !flag {
-                    return Err(RPCError::internal_error("da fee not found".to_string()));
+                // the fetcher charges the DA fee to every accepted deposit out
+                // of the outputs to the fee address, configs pay none
+                let (fee_address, da_fee) = self.da_info()?;
+                let paid = tx
+                    .output
+                    .iter()
+                    .filter(|txout| {
+                        Ok(fee_address.clone())
+                            == Address::from_script(&txout.script_pubkey, self.network)
+                    })
+                    .try_fold(Amount::ZERO, |paid, txout| paid.checked_add(txout.value))
+                    .ok_or(RPCError::invalid_params())?;
+                let required = da_fee
+                    .checked_mul(deposit_envelopes(&tx))
+                    .ok_or(RPCError::invalid_params())?;
+                if paid < required {
+                    return Err(RPCError::internal_error(format!(
+                        "da fee required {}, paid {}",
+                        required, paid
+                    )));
                 }
 
                 let txid = self.client.send_raw_transaction(&tx).map_err(|e| {
@@ -104,11 +182,46 @@ impl Handle for NovoHandle {
 
                 Ok(Some(Value::String(format!("{}", txid))))
             }
-            "novo_getDaInfo" => Ok(Some(json!({
-                "address": &self.fee_address,
-                "fee": self.da_fee,
-
-            }))),
+            "novo_getDaInfo" => {
+                let (fee_address, da_fee) = self.da_info()?;
+                Ok(Some(json!({
+                    "address": &fee_address,
+                    "fee": da_fee,
+                })))
+            }
+            "novo_getChainConfigs" => {
+                let schedule = self
+                    .schedule
+                    .read()
+                    .map_err(|e| RPCError::internal_error(format!("chain config lock:{e}")))?;
+                let configs = serde_json::to_value(schedule.history().collect::<Vec<_>>())
+                    .map_err(|e| RPCError::internal_error(format!("chain config:{e}")))?;
+                Ok(Some(configs))
+            }
+            "novo_getWithdrawal" => {
+                let id = req.ok_or(RPCError::invalid_params())?.into_single_param()?;
+                let withdrawals = self
+                    .withdrawals
+                    .read()
+                    .map_err(|e| RPCError::internal_error(format!("withdrawal lock:{e}")))?;
+                let withdrawal = serde_json::to_value(withdrawals.get(&id))
+                    .map_err(|e| RPCError::internal_error(format!("withdrawal:{e}")))?;
+                Ok(Some(withdrawal))
+            }
+            "novo_getWithdrawals" => {
+                let from = req
+                    .ok_or(RPCError::invalid_params())?
+                    .into_single_param()?
+                    .parse::<H160>()
+                    .map_err(|_| RPCError::invalid_params())?;
+                let withdrawals = self
+                    .withdrawals
+                    .read()
+                    .map_err(|e| RPCError::internal_error(format!("withdrawal lock:{e}")))?;
+                let withdrawals = serde_json::to_value(withdrawals.by_address(from))
+                    .map_err(|e| RPCError::internal_error(format!("withdrawal:{e}")))?;
+                Ok(Some(withdrawals))
+            }
             _ => Err(RPCError::unknown_method()),
         }
     }
@@ -16,10 +16,29 @@ use bitcoin::{
 use bitcoincore_rpc::{
     json::SignRawTransactionInput, jsonrpc::serde_json::Value, Client as BitcoincoreClient, RpcApi,
 };
-use electrum_client::{Client as ElectrumClient, ElectrumApi, ListUnspentRes};
+/// Unspent outputs payouts are built from.
+pub use electrum_client::ListUnspentRes;
+use electrum_client::{Client as ElectrumClient, ElectrumApi};
 use ethers::{types::H160, utils::keccak256};
 use json_rpc_server::call;
 
+/// Change below this is not standard.
+const CHANGE_DUST_LIMIT: Amount = Amount::from_sat(546);
+
+/// Virtual size of a transaction spending `inputs` p2wpkh outputs to
+/// `outputs`, with signatures at their largest.
+fn payout_vsize(inputs: usize, outputs: &[ScriptBuf]) -> u64 {
+    // version, locktime, segwit marker and flag, input and output counts
+    let overhead = 4 + 4 + 1 + 3 + 3;
+    // outpoint, sequence, empty script sig and a quarter of the witness
+    let input = 41 + 27;
+    let outputs = outputs
+        .iter()
+        .map(|script| 9 + script.len() as u64)
+        .sum::<u64>();
+    overhead + input * inputs as u64 + outputs
+}
+
 pub struct BtcTransactionBuilder {
     electrum_client: ElectrumClient,
     pub bitcoincore_client: Arc<BitcoincoreClient>,
@@ -196,4 +215,97 @@ impl BtcTransactionBuilder {
         // Get the signed transaction.
         Ok(sighasher.into_transaction().clone())
     }
+
+    /// Builds and signs a transaction paying `payouts` from the p2wpkh `script`
+    /// of `private_key` at `fee_rate` sat/vB, spending `unspents` in order.
+    /// The change goes back to `script` unless it is dust, the inputs signal
+    /// replaceability so the payout can be bumped.
+    pub fn build_payout(
+        &self,
+        private_key: PrivateKey,
+        script: ScriptBuf,
+        unspents: Vec<ListUnspentRes>,
+        payouts: &[(ScriptBuf, Amount)],
+        fee_rate: u64,
+    ) -> Result<Transaction> {
+        let total = payouts.iter().map(|(_, amount)| *amount).sum::<Amount>();
+        let scripts = payouts
+            .iter()
+            .map(|(script_pubkey, _)| script_pubkey.clone())
+            .collect::<Vec<_>>();
+        let with_change = [scripts.as_slice(), &[script.clone()]].concat();
+        let fee_without_change =
+            |inputs: usize| Amount::from_sat(fee_rate * payout_vsize(inputs, &scripts));
+        let fee = |inputs: usize| Amount::from_sat(fee_rate * payout_vsize(inputs, &with_change));
+
+        let mut input = Vec::new();
+        let mut amounts = Vec::new();
+        let mut sum_amount = Amount::ZERO;
+        for it in unspents.iter() {
+            if sum_amount >= total + fee(input.len()) {
+                break;
+            }
+            sum_amount += Amount::from_sat(it.value);
+            amounts.push(Amount::from_sat(it.value));
+            input.push(TxIn {
+                previous_output: OutPoint {
+                    txid: it.tx_hash,
+                    vout: it.tx_pos as u32,
+                },
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                script_sig: ScriptBuf::default(),
+                witness: Witness::new(),
+            });
+        }
+
+        if sum_amount < total + fee_without_change(input.len()) {
+            return Err(anyhow!("Insufficient balance"));
+        }
+        let mut output = payouts
+            .iter()
+            .map(|(script_pubkey, value)| TxOut {
+                value: *value,
+                script_pubkey: script_pubkey.clone(),
+            })
+            .collect::<Vec<_>>();
+        // change below dust, or not paying for its own output, is left to the fee
+        let change = sum_amount
+            .checked_sub(total + fee(input.len()))
+            .unwrap_or(Amount::ZERO);
+        if change >= CHANGE_DUST_LIMIT {
+            output.push(TxOut {
+                value: change,
+                script_pubkey: script.clone(),
+            });
+        }
+
+        let mut unsigned_tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input,
+            output,
+        };
+        let sighash_type = EcdsaSighashType::All;
+        let secp = Secp256k1::new();
+
+        let pk = private_key.public_key(&secp);
+        let mut sighasher = SighashCache::new(&mut unsigned_tx);
+
+        for (index, amount) in amounts.into_iter().enumerate() {
+            let sighash = sighasher.p2wpkh_signature_hash(index, &script, amount, sighash_type)?;
+
+            let msg = Message::from(sighash);
+            let signature = Signature {
+                sig: secp.sign_ecdsa(&msg, &private_key.inner),
+                hash_ty: sighash_type,
+            };
+
+            let witness = sighasher
+                .witness_mut(index)
+                .ok_or(anyhow!("{} witness is none", index))?;
+            *witness = Witness::p2wpkh(&signature, &pk.inner);
+        }
+
+        Ok(sighasher.into_transaction().clone())
+    }
 }
@@ -2,5 +2,3 @@
 
 pub mod btc;
 pub mod eth;
-
-pub const SAT2WEI: u64 = 10000000000;
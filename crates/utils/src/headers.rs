@@ -0,0 +1,178 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use bitcoin::{
+    block::Header,
+    consensus::{deserialize, serialize},
+};
+
+const HEADER_SIZE: usize = 80;
+const MEDIAN_TIME_SPAN: u64 = 11;
+
+/// BTC headers from `first` on, persisted as concatenated 80 byte headers.
+pub struct HeaderStore {
+    path: PathBuf,
+    first: u64,
+    headers: Vec<Header>,
+    /// Headers of the median time span before `first`, fetched on load.
+    prior: Vec<Header>,
+}
+
+impl HeaderStore {
+    /// Loads the headers below `next`, those above were written before a
+    /// restart and get fetched again. Those missing, in datadirs created
+    /// before the headers were stored, are backfilled with `fetch`, which
+    /// also gets those of the median time span before `first`.
+    pub fn load(
+        path: PathBuf,
+        first: u64,
+        next: u64,
+        fetch: impl Fn(u64) -> Result<Header>,
+    ) -> Result<Self> {
+        let mut headers = vec![];
+        if path.exists() {
+            let data = fs::read(&path)?;
+            for chunk in data.chunks_exact(HEADER_SIZE) {
+                if first + headers.len() as u64 >= next {
+                    break;
+                }
+                headers.push(deserialize(chunk)?);
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len((headers.len() * HEADER_SIZE) as u64)?;
+        }
+        let prior = (first.saturating_sub(MEDIAN_TIME_SPAN)..first)
+            .map(&fetch)
+            .collect::<Result<Vec<_>>>()?;
+        let mut store = Self {
+            path,
+            first,
+            headers,
+            prior,
+        };
+        if store.next() < next {
+            log::info!("backfill headers {} to {}", store.next(), next - 1);
+        }
+        while store.next() < next {
+            let height = store.next();
+            store.push(height, fetch(height)?)?;
+        }
+        Ok(store)
+    }
+
+    pub fn push(&mut self, height: u64, header: Header) -> Result<()> {
+        if height != self.next() {
+            return Err(anyhow!("header {} expected, got {}", self.next(), height));
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&serialize(&header))?;
+        self.headers.push(header);
+        Ok(())
+    }
+
+    pub fn get(&self, height: u64) -> Option<&Header> {
+        let index = height.checked_sub(self.first)?;
+        self.headers.get(usize::try_from(index).ok()?)
+    }
+
+    /// Median time of the 11 headers before `height`, fewer near the BTC
+    /// genesis, as BIP113 defines it. BTC headers are valid only above the
+    /// median time of their parent, so unlike header times it never goes
+    /// backwards.
+    pub fn median_time_past(&self, height: u64) -> Option<u64> {
+        let from = height.saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = (from..height)
+            .map(|h| self.get_or_prior(h).map(|header| header.time as u64))
+            .collect::<Option<Vec<_>>>()?;
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    fn get_or_prior(&self, height: u64) -> Option<&Header> {
+        match height.checked_sub(self.first - self.prior.len() as u64) {
+            Some(index) if height < self.first => self.prior.get(index as usize),
+            _ => self.get(height),
+        }
+    }
+
+    pub fn next(&self) -> u64 {
+        self.first + self.headers.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use bitcoin::{block::Version, hashes::Hash, BlockHash, CompactTarget, TxMerkleNode};
+
+    use super::*;
+
+    // valid header times going backwards every other block
+    const TIMES: [u32; 30] = [
+        100, 110, 105, 120, 115, 130, 125, 140, 135, 150, 145, 160, 155, 170, 165, 180, 175, 190,
+        185, 200, 195, 210, 205, 220, 215, 230, 225, 240, 235, 250,
+    ];
+
+    fn header(height: u64) -> Result<Header> {
+        Ok(Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: TIMES[height as usize],
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 0,
+        })
+    }
+
+    fn median(times: &[u32]) -> u64 {
+        let mut times = times.to_vec();
+        times.sort_unstable();
+        times[times.len() / 2] as u64
+    }
+
+    #[test]
+    fn median_time_past_from_first() {
+        let path = env::temp_dir().join("header_store_mtp.meta");
+        let _ = fs::remove_file(&path);
+        let mut store = HeaderStore::load(path.clone(), 15, 15, header).unwrap();
+        // the span before the first header is fetched, not stored
+        assert_eq!(store.get(14), None);
+        assert_eq!(store.median_time_past(15), Some(median(&TIMES[4..15])));
+
+        let mut last = 0;
+        for height in 15..TIMES.len() as u64 {
+            store.push(height, header(height).unwrap()).unwrap();
+            let mtp = store.median_time_past(height).unwrap();
+            assert_eq!(mtp, median(&TIMES[height as usize - 11..height as usize]));
+            assert!(mtp >= last);
+            last = mtp;
+        }
+        assert_eq!(store.median_time_past(TIMES.len() as u64 + 1), None);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn median_time_past_near_genesis() {
+        let path = env::temp_dir().join("header_store_mtp_genesis.meta");
+        let _ = fs::remove_file(&path);
+        let store = HeaderStore::load(path.clone(), 1, 6, header).unwrap();
+
+        assert_eq!(store.median_time_past(0), None);
+        assert_eq!(store.median_time_past(1), Some(100));
+        assert_eq!(store.median_time_past(3), Some(median(&TIMES[..3])));
+        assert_eq!(store.median_time_past(6), Some(median(&TIMES[..6])));
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -5,3 +5,9 @@ pub use script_code::*;
 
 mod utils;
 pub use utils::*;
+
+mod headers;
+pub use headers::*;
+
+mod origin;
+pub use origin::*;
@@ -0,0 +1,129 @@
+use std::{fs, mem::size_of, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+const ORIGIN_SIZE: usize = size_of::<u64>() * 2 + size_of::<u32>() * 2;
+
+/// The BTC block an EVM block was produced from, a BTC block may be split
+/// into `count` EVM blocks of which this is `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L1Origin {
+    pub number: u64,
+    pub btc_height: u64,
+    pub index: u32,
+    pub count: u32,
+}
+
+impl L1Origin {
+    /// Whether the remaining EVM blocks of the BTC block are still to produce.
+    pub fn is_partial(&self) -> bool {
+        self.index + 1 < self.count
+    }
+
+    #[cfg(test)]
+    fn encode(&self) -> [u8; ORIGIN_SIZE] {
+        let mut ret = [0u8; ORIGIN_SIZE];
+        ret[..8].copy_from_slice(&self.number.to_be_bytes());
+        ret[8..16].copy_from_slice(&self.btc_height.to_be_bytes());
+        ret[16..20].copy_from_slice(&self.index.to_be_bytes());
+        ret[20..].copy_from_slice(&self.count.to_be_bytes());
+        ret
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        let u64_at = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        Self {
+            number: u64_at(0),
+            btc_height: u64_at(8),
+            index: u32_at(16),
+            count: u32_at(20),
+        }
+    }
+}
+
+/// Reads the L1 origins up to block `latest` from the fixed size records
+/// written before the origins were kept in vsdb. Those above were written
+/// before a restart and get produced again.
+pub fn read_legacy_origins(path: &Path, latest: u64) -> Result<Vec<L1Origin>> {
+    let data = fs::read(path)?;
+    let mut origins: Vec<L1Origin> = vec![];
+    for chunk in data.chunks_exact(ORIGIN_SIZE) {
+        let origin = L1Origin::decode(chunk);
+        if origin.number > latest {
+            break;
+        }
+        if let Some(last) = origins.last() {
+            if origin.number != last.number + 1 {
+                return Err(anyhow!(
+                    "origin of block {} expected, got {}",
+                    last.number + 1,
+                    origin.number
+                ));
+            }
+        }
+        origins.push(origin);
+    }
+    Ok(origins)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn origin(number: u64, btc_height: u64, index: u32, count: u32) -> L1Origin {
+        L1Origin {
+            number,
+            btc_height,
+            index,
+            count,
+        }
+    }
+
+    fn write_legacy(name: &str, origins: &[L1Origin]) -> std::path::PathBuf {
+        let path = env::temp_dir().join(name);
+        let data = origins
+            .iter()
+            .flat_map(L1Origin::encode)
+            .collect::<Vec<_>>();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_up_to_latest() {
+        let origins = (6..10)
+            .enumerate()
+            .map(|(i, number)| origin(number, 105 + i as u64 / 2, i as u32 % 2, 2))
+            .collect::<Vec<_>>();
+        let path = write_legacy("l1_origin_legacy_read.meta", &origins);
+
+        assert_eq!(read_legacy_origins(&path, 9).unwrap(), origins);
+        let kept = read_legacy_origins(&path, 7).unwrap();
+        assert_eq!(kept, &origins[..2]);
+        assert!(kept[0].is_partial());
+        assert!(!kept[1].is_partial());
+        // a latest block below the first record drops them all
+        assert!(read_legacy_origins(&path, 5).unwrap().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_rejects_gaps() {
+        let path = write_legacy(
+            "l1_origin_legacy_gap.meta",
+            &[origin(6, 105, 0, 1), origin(8, 106, 0, 1)],
+        );
+        assert!(read_legacy_origins(&path, 8).is_err());
+        assert_eq!(
+            read_legacy_origins(&path, 7).unwrap(),
+            &[origin(6, 105, 0, 1)]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
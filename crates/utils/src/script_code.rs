@@ -8,11 +8,14 @@ pub struct ScriptCode {
     pub version: u8,
     pub filling: u8,
     pub hash: Vec<u8>,
+    /// Last BTC height the envelope can be included at, carried in 4 optional
+    /// trailing bytes.
+    pub valid_until: Option<u32>,
 }
 
 impl ScriptCode {
     pub fn decode(data: &[u8]) -> Result<Self> {
-        if data.len() != 40 {
+        if data.len() != 40 && data.len() != 44 {
             return Err(anyhow!("Not long enough"));
         }
 
@@ -22,7 +25,11 @@ impl ScriptCode {
             da_type: data[5],
             version: data[6],
             filling: data[7],
-            hash: data[8..].to_vec(),
+            hash: data[8..40].to_vec(),
+            valid_until: data
+                .get(40..)
+                .filter(|v| !v.is_empty())
+                .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]])),
         };
         log::info!("decode script code:{:#?}", ret);
         Ok(ret)
@@ -34,25 +41,29 @@ impl ScriptCode {
         hash
     }
 
-    pub fn encode(&self) -> [u8; 40] {
-        let mut code: [u8; 40] = [0; 40];
+    pub fn encode(&self) -> Vec<u8> {
+        let mut code = vec![0; 40];
         let chain_id = self.chain_id.to_be_bytes();
         code[..4].copy_from_slice(&chain_id[..4]);
         code[4] = self.tx_type;
         code[5] = self.da_type;
-        code[6] = self.filling;
+        code[6] = self.version;
+        code[7] = self.filling;
         code[8..(self.hash.len() + 8)].copy_from_slice(&self.hash);
+        if let Some(valid_until) = self.valid_until {
+            code.extend_from_slice(&valid_until.to_be_bytes());
+        }
         code
     }
 
-    pub fn check(&self, chain_id: u32, da_tys: Vec<u8>) -> Result<()> {
+    pub fn check(&self, chain_id: u32, version: u8, da_tys: Vec<u8>) -> Result<()> {
         if 1 != self.tx_type && self.chain_id != chain_id {
             Err(anyhow!("chain id error:{} {}", self.chain_id, chain_id))
         } else if 0 != self.tx_type && 1 != self.tx_type {
             Err(anyhow!("tx type error:{}", self.tx_type))
         } else if !da_tys.contains(&self.da_type) {
             Err(anyhow!("da type error:{:?},{}", da_tys, self.da_type))
-        } else if 0 != self.version {
+        } else if version != self.version {
             Err(anyhow!("version error:{}", self.version))
         } else if 0 != self.filling {
             Err(anyhow!("filling error:{}", self.filling))
@@ -61,3 +72,60 @@ impl ScriptCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script_code(valid_until: Option<u32>) -> ScriptCode {
+        ScriptCode {
+            chain_id: 0x01020304,
+            tx_type: 1,
+            da_type: 2,
+            version: 3,
+            filling: 0,
+            hash: (0..32).collect(),
+            valid_until,
+        }
+    }
+
+    #[test]
+    fn round_trip_without_expiry() {
+        let code = script_code(None).encode();
+        assert_eq!(code.len(), 40);
+        assert_eq!(&code[..8], &[1, 2, 3, 4, 1, 2, 3, 0]);
+
+        let decoded = ScriptCode::decode(&code).unwrap();
+        assert_eq!(decoded.chain_id, 0x01020304);
+        assert_eq!(decoded.tx_type, 1);
+        assert_eq!(decoded.da_type, 2);
+        assert_eq!(decoded.version, 3);
+        assert_eq!(decoded.filling, 0);
+        assert_eq!(decoded.hash, (0..32).collect::<Vec<u8>>());
+        assert_eq!(decoded.valid_until, None);
+        assert_eq!(decoded.encode(), code);
+    }
+
+    #[test]
+    fn round_trip_with_expiry() {
+        let code = script_code(Some(840000)).encode();
+        assert_eq!(code.len(), 44);
+        assert_eq!(&code[40..], &840000u32.to_be_bytes());
+
+        let decoded = ScriptCode::decode(&code).unwrap();
+        assert_eq!(decoded.valid_until, Some(840000));
+        assert_eq!(decoded.da_hash()[0], 2);
+        assert_eq!(&decoded.da_hash()[1..], &code[8..40]);
+        assert_eq!(decoded.encode(), code);
+    }
+
+    #[test]
+    fn decode_other_lengths() {
+        let code = script_code(Some(1)).encode();
+        for len in [0, 39, 41, 43, 45] {
+            let mut data = code.clone();
+            data.resize(len, 0);
+            assert!(ScriptCode::decode(&data).is_err());
+        }
+    }
+}
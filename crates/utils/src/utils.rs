@@ -1,4 +1,9 @@
-use std::str::FromStr;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    str::FromStr,
+};
 
 use anyhow::Result;
 
@@ -21,3 +26,18 @@ pub fn parse_sk(sk: &str, network: &str) -> Result<(PrivateKey, Address)> {
 
     Ok((private_key, address))
 }
+
+/// Writes `data` to a temporary file renamed over `path`, so after a crash
+/// `path` holds either the old or the new content. Both are synced to disk
+/// before returning, the rename with the directory.
+pub fn write_atomic(path: &Path, data: impl AsRef<[u8]>) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data.as_ref())?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -1,11 +1,16 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
-use crate::{generate_config::GenerateConfig, node::Node};
+use crate::{
+    convert_genesis::ConvertGenesis, dump_state::DumpState, generate_config::GenerateConfig,
+    node::Node,
+};
 
 #[derive(Subcommand)]
 pub enum Command {
     GenCfg(GenerateConfig),
+    ConvertGenesis(ConvertGenesis),
+    DumpState(DumpState),
     Node(Node),
 }
 
@@ -19,6 +24,8 @@ impl CommandLine {
     pub async fn exeute(&self) -> Result<()> {
         match &self.command {
             Command::GenCfg(c) => c.exeute(),
+            Command::ConvertGenesis(c) => c.exeute(),
+            Command::DumpState(c) => c.exeute(),
             Command::Node(c) => c.exeute().await,
         }
     }
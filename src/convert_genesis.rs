@@ -0,0 +1,50 @@
+use std::fs;
+
+use anyhow::Result;
+use clap::Args;
+use config::ChainConfig;
+
+#[derive(Debug, Args)]
+pub struct ConvertGenesis {
+    /// geth genesis.json to import, or the chain config to export with
+    /// --reverse
+    #[clap(short, long)]
+    genesis: String,
+
+    /// write the chain config, or the genesis.json, here instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+
+    #[clap(short, long)]
+    bin_hash: Option<String>,
+
+    #[clap(short, long, default_value_t = 0)]
+    activation_height: u64,
+
+    /// export a chain config as a geth genesis.json
+    #[clap(long)]
+    reverse: bool,
+}
+
+impl ConvertGenesis {
+    pub fn exeute(&self) -> Result<()> {
+        let data = fs::read(&self.genesis)?;
+
+        let json = if self.reverse {
+            let cfg: ChainConfig = serde_json::from_slice(&data)?;
+            serde_json::to_string_pretty(&cfg.to_genesis()?)?
+        } else {
+            let mut cfg = ChainConfig::from_genesis(serde_json::from_slice(&data)?)?;
+            if let Some(bin_hash) = &self.bin_hash {
+                cfg.bin_hash = bin_hash.parse()?;
+            }
+            cfg.activation_height = self.activation_height;
+            serde_json::to_string_pretty(&cfg)?
+        };
+        match &self.output {
+            Some(output) => fs::write(output, json)?,
+            None => println!("{}", json),
+        }
+        Ok(())
+    }
+}
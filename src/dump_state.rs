@@ -0,0 +1,190 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use config::{Account, PROTOCOL_VERSION};
+use rt_evm::{
+    executor::{Backend, RTEvmExecutorAdapter},
+    model::{
+        traits::BlockStorage,
+        types::{ExecutorContext, TransactionAction, H160, H256, U256},
+    },
+    EvmRuntime,
+};
+
+use crate::meta::MetaStore;
+
+/// Exports the EVM state of a datadir as a chain config.
+///
+/// The state trie only keeps hashed keys, so accounts and storage slots are
+/// discovered from the chain history: accounts and slots of every published
+/// config, transaction senders and recipients, created contracts and log
+/// emitters. Accounts only reached by internal calls are missed, and contract
+/// storage written at runtime can not be listed, so contracts are refused
+/// unless `--partial` is given.
+#[derive(Debug, Args)]
+pub struct DumpState {
+    #[clap(short, long)]
+    datadir: String,
+
+    /// write the chain config here instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+
+    /// only export these accounts
+    #[clap(short, long)]
+    address: Vec<String>,
+
+    /// export contracts with the storage slots found, instead of failing
+    #[clap(long)]
+    partial: bool,
+}
+
+impl DumpState {
+    pub fn exeute(&self) -> Result<()> {
+        let datadir = PathBuf::from(&self.datadir);
+        if !datadir.exists() {
+            return Err(anyhow!("datadir not found:{}", self.datadir));
+        }
+        vsdb::vsdb_set_base_dir(&self.datadir).map_err(|e| anyhow!(e.to_string()))?;
+
+        let evm_rt = EvmRuntime::restore()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or(anyhow!("restore data error"))?;
+        let meta = MetaStore::open(&datadir)?;
+        let schedule = meta.schedule();
+
+        let mut slots = BTreeMap::<H160, BTreeSet<U256>>::new();
+        for cfg in schedule.history() {
+            for (address, account) in cfg.accounts.iter() {
+                slots
+                    .entry(*address)
+                    .or_default()
+                    .extend(account.storage.iter().flat_map(|s| s.keys().cloned()));
+            }
+        }
+
+        let storage = evm_rt.copy_storage_handler();
+        let header = storage
+            .get_latest_block_header()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        for number in 0..=header.number {
+            let block = storage
+                .get_block(number)
+                .map_err(|e| anyhow!(e.to_string()))?
+                .ok_or(anyhow!("block {} not found", number))?;
+            let txs = storage
+                .get_transactions(number, &block.tx_hashes)
+                .map_err(|e| anyhow!(e.to_string()))?;
+            let receipts = storage
+                .get_receipts(number, &block.tx_hashes)
+                .map_err(|e| anyhow!(e.to_string()))?;
+
+            for tx in txs.into_iter().flatten() {
+                slots.entry(tx.sender).or_default();
+                if let TransactionAction::Call(to) = tx.transaction.unsigned.action() {
+                    slots.entry(*to).or_default();
+                }
+            }
+            for receipt in receipts.into_iter().flatten() {
+                if let Some(address) = receipt.contract_address {
+                    slots.entry(address).or_default();
+                }
+                for log in receipt.logs.iter() {
+                    slots.entry(log.address).or_default();
+                }
+            }
+        }
+
+        if !self.address.is_empty() {
+            let filter = self
+                .address
+                .iter()
+                .map(|a| a.parse::<H160>())
+                .collect::<Result<BTreeSet<_>, _>>()?;
+            slots.retain(|address, _| filter.contains(address));
+            for address in filter {
+                slots.entry(address).or_default();
+            }
+        }
+
+        let trie = evm_rt.copy_trie_handler();
+        let backend = RTEvmExecutorAdapter::from_root(
+            header.state_root,
+            &trie,
+            &storage,
+            ExecutorContext::from(header.clone()),
+        )
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+        let mut accounts = BTreeMap::new();
+        for (address, keys) in slots {
+            if !backend.exists(address) {
+                continue;
+            }
+            let basic = backend.basic(address);
+            let code = backend.code(address);
+            let storage = keys
+                .into_iter()
+                .filter_map(|key| {
+                    let mut index = H256::zero();
+                    key.to_big_endian(index.as_bytes_mut());
+                    let value = backend.storage(address, index);
+                    (!value.is_zero()).then(|| (key, U256::from_big_endian(value.as_bytes())))
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            accounts.insert(
+                address,
+                Account {
+                    balance: Some(basic.balance),
+                    nonce: Some(basic.nonce),
+                    code: (!code.is_empty()).then(|| code.into()),
+                    storage: (!storage.is_empty()).then_some(storage),
+                },
+            );
+        }
+
+        // slots come from published configs only, runtime writes are unknown
+        let contracts = accounts
+            .iter()
+            .filter(|(_, account)| account.code.is_some())
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        if !contracts.is_empty() && !self.partial {
+            return Err(anyhow!(
+                "storage of contracts {:?} can not be listed, pass --partial to export the slots of published configs only",
+                contracts
+            ));
+        }
+        for address in contracts {
+            log::warn!("storage of contract {:?} may be incomplete", address);
+        }
+        log::warn!("accounts only reached by internal calls are not exported");
+
+        // configs scheduled above the latest block are not active yet
+        let btc_height = meta
+            .origin(header.number)
+            .ok_or(anyhow!("l1 origin of block {} not found", header.number))?
+            .btc_height;
+        let mut cfg = schedule
+            .active(btc_height)
+            .cloned()
+            .ok_or(anyhow!("chain config not found at {}", btc_height))?;
+        cfg.activation_height = 0;
+        // the accounts are seeded from protocol version 1 on
+        cfg.protocol_version = cfg.protocol_version.max(PROTOCOL_VERSION);
+        cfg.accounts = accounts;
+
+        let json = serde_json::to_string_pretty(&cfg)?;
+        match &self.output {
+            Some(output) => fs::write(output, json)?,
+            None => println!("{}", json),
+        }
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use std::{fs, path::PathBuf};
 
 use anyhow::{anyhow, Result};
 use clap::Args;
-use config::{BtcConfig, Config};
+use config::{BtcConfig, Config, WithdrawalConfig};
 #[cfg(feature = "celestia")]
 use da::CelestiaConfig;
 use da::DaType;
@@ -67,6 +67,16 @@ impl GenerateConfig {
                     da_fee: 100,
                     fee_address: "bcrt1qhwkqamxr93phyhlc82elqm2n8hufr8xls0djwn".to_string(),
                 },
+                withdrawal: Some(WithdrawalConfig {
+                    operator_sk:
+                        "0x24e196d2883a86572d43f7896d6ffd0c11a456afba1c1c3180674b6f0624cace"
+                            .to_string(),
+                    confirmations: 6,
+                    fee_rate: 1,
+                    max_batch: 100,
+                    bump_after: 6,
+                }),
+                genesis: None,
             };
             Ok(fs::write(file, toml::to_string_pretty(&cfg)?)?)
         }
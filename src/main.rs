@@ -1,7 +1,10 @@
 #![deny(warnings, unused_crate_dependencies)]
 
 mod command_line;
+mod convert_genesis;
+mod dump_state;
 mod generate_config;
+mod meta;
 mod node;
 
 use anyhow::Result;
@@ -0,0 +1,389 @@
+use std::{
+    fs,
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use bridge::Credit;
+use config::ChainConfigSchedule;
+use rt_evm::model::types::{H256, U256};
+use serde::{Deserialize, Serialize};
+use utils::{read_legacy_origins, write_atomic, L1Origin};
+use vsdb::{MapxOrd, Orphan};
+
+const META_FILE: &str = "FETCHER_RUNTIME_vsdb.meta";
+const LEGACY_HEIGHT_FILE: &str = "FETCHER_RUNTIME_height.meta";
+const LEGACY_CONFIG_FILE: &str = "FETCHER_RUNTIME_chain_cfg.meta";
+const LEGACY_ORIGIN_FILE: &str = "FETCHER_RUNTIME_l1_origins.meta";
+
+/// What a datadir was created for, the node refuses to start against another
+/// bitcoind or network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DatadirInfo {
+    /// Network as a bitcoind `-chain` argument.
+    pub(crate) network: String,
+    /// BTC height the genesis config envelope was searched from.
+    pub(crate) start: u64,
+    /// BTC block holding the genesis config envelope.
+    pub(crate) genesis_hash: H256,
+    /// BTC transaction of the genesis config envelope, missing in datadirs
+    /// created before it was recorded.
+    #[serde(default)]
+    pub(crate) genesis_txid: Option<H256>,
+    pub(crate) chain_id: u32,
+}
+
+fn no_datadir_info() -> Orphan<Option<DatadirInfo>> {
+    Orphan::new(None)
+}
+
+fn no_credits() -> Orphan<Option<(u64, Vec<Credit>)>> {
+    Orphan::new(None)
+}
+
+fn no_system_gas() -> Orphan<Option<(u64, U256)>> {
+    Orphan::new(None)
+}
+
+/// Resume state of the node, kept in the vsdb backend of the EVM runtime.
+/// Only the handles go to `META_FILE`, rewritten when one is added. The
+/// changes are not atomic with the EVM blocks: they are flushed after every
+/// produced block and when the store is dropped, and the origins recorded
+/// for a block that was not produced are dropped on restart.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MetaStore {
+    /// BTC height of the first EVM block.
+    first_height: Orphan<u64>,
+    schedule: Orphan<ChainConfigSchedule>,
+    /// Hashes of the processed BTC blocks by height.
+    block_hashes: MapxOrd<u64, H256>,
+    /// Missing in datadirs created before it was recorded.
+    #[serde(default = "no_datadir_info")]
+    info: Orphan<Option<DatadirInfo>>,
+    /// L1 origins of the EVM blocks by number. Blocks without one, produced
+    /// before the mapping existed, are one per BTC block from `first_height`.
+    #[serde(default = "MapxOrd::new")]
+    origins: MapxOrd<u64, L1Origin>,
+    /// Credits carried from a block to the next one, with the number of the
+    /// block. Missing in datadirs created before they were recorded.
+    #[serde(default = "no_credits")]
+    credits: Orphan<Option<(u64, Vec<Credit>)>>,
+    /// Gas reserved for the system deposits of the first EVM block of a BTC
+    /// block, with its height. Missing in datadirs created before it was
+    /// recorded.
+    #[serde(default = "no_system_gas")]
+    system_gas: Orphan<Option<(u64, U256)>>,
+}
+
+impl MetaStore {
+    pub(crate) fn create(
+        datadir: &Path,
+        first_height: u64,
+        schedule: &ChainConfigSchedule,
+        info: Option<DatadirInfo>,
+    ) -> Result<Self> {
+        let store = Self {
+            first_height: Orphan::new(first_height),
+            schedule: Orphan::new(schedule.clone()),
+            block_hashes: MapxOrd::new(),
+            info: Orphan::new(info),
+            origins: MapxOrd::new(),
+            credits: no_credits(),
+            system_gas: no_system_gas(),
+        };
+        store.flush();
+        write_atomic(&datadir.join(META_FILE), serde_json::to_vec(&store)?)?;
+        Ok(store)
+    }
+
+    /// Opens the store of `datadir` without migrating it, a datadir keeping
+    /// the state in .meta files has to be loaded by the node first.
+    pub(crate) fn open(datadir: &Path) -> Result<Self> {
+        let path = datadir.join(META_FILE);
+        if !path.exists() || datadir.join(LEGACY_ORIGIN_FILE).exists() {
+            return Err(anyhow!(
+                "legacy datadir {}, start the node on it to migrate it",
+                datadir.display()
+            ));
+        }
+        Ok(serde_json::from_slice(&fs::read(&path)?)?)
+    }
+
+    /// Loads the store of `datadir`, a datadir keeping the state in .meta
+    /// files is migrated.
+    pub(crate) fn load(datadir: &Path) -> Result<Self> {
+        let path = datadir.join(META_FILE);
+        if path.exists() {
+            let data = fs::read(&path)?;
+            let store: Self = serde_json::from_slice(&data)?;
+            // handles added since the file was written are kept from now on
+            let encoded = serde_json::to_vec(&store)?;
+            if encoded != data {
+                store.flush();
+                write_atomic(&path, encoded)?;
+            }
+            return Ok(store);
+        }
+
+        let legacy = |name: &str| -> PathBuf { datadir.join(name) };
+        let first_height =
+            <[u8; size_of::<u64>()]>::try_from(fs::read(legacy(LEGACY_HEIGHT_FILE))?)
+                .map(u64::from_be_bytes)
+                .map_err(|_| anyhow!("start height read error"))?;
+        // written by releases before the schedule as the genesis config alone
+        let data = fs::read(legacy(LEGACY_CONFIG_FILE))?;
+        let schedule = match serde_json::from_slice::<ChainConfigSchedule>(&data) {
+            Ok(schedule) => schedule,
+            Err(_) => ChainConfigSchedule::new(first_height - 1, serde_json::from_slice(&data)?),
+        };
+
+        log::info!("migrate metadata into vsdb");
+        let store = Self::create(datadir, first_height, &schedule, None)?;
+        fs::remove_file(legacy(LEGACY_HEIGHT_FILE))?;
+        fs::remove_file(legacy(LEGACY_CONFIG_FILE))?;
+        Ok(store)
+    }
+
+    pub(crate) fn first_height(&self) -> u64 {
+        self.first_height.get_value()
+    }
+
+    pub(crate) fn schedule(&self) -> ChainConfigSchedule {
+        self.schedule.get_value()
+    }
+
+    pub(crate) fn set_schedule(&mut self, schedule: &ChainConfigSchedule) {
+        self.schedule.set_value(schedule.clone());
+    }
+
+    pub(crate) fn info(&self) -> Option<DatadirInfo> {
+        self.info.get_value()
+    }
+
+    pub(crate) fn set_info(&mut self, info: DatadirInfo) {
+        self.info.set_value(Some(info));
+    }
+
+    pub(crate) fn block_hash(&self, height: u64) -> Option<H256> {
+        self.block_hashes.get(&height)
+    }
+
+    pub(crate) fn set_block_hash(&mut self, height: u64, hash: H256) {
+        self.block_hashes.insert(&height, &hash);
+    }
+
+    /// Drops the origins above block `latest`, recorded before a restart
+    /// for blocks that get produced again. Origins kept in the legacy file are
+    /// migrated.
+    pub(crate) fn load_origins(&mut self, datadir: &Path, latest: u64) -> Result<()> {
+        let path = datadir.join(LEGACY_ORIGIN_FILE);
+        if path.exists() {
+            log::info!("migrate l1 origins into vsdb");
+            for origin in read_legacy_origins(&path, latest)? {
+                self.origins.insert(&origin.number, &origin);
+            }
+            self.flush();
+            fs::remove_file(path)?;
+        }
+
+        let above = self
+            .origins
+            .range((latest + 1)..)
+            .map(|(number, _)| number)
+            .collect::<Vec<_>>();
+        for number in above {
+            self.origins.remove(&number);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn origin(&self, number: u64) -> Option<L1Origin> {
+        match self.origins.first() {
+            Some((first, _)) if number >= first => self.origins.get(&number),
+            _ => Some(L1Origin {
+                number,
+                btc_height: (self.first_height() + number).checked_sub(1)?,
+                index: 0,
+                count: 1,
+            }),
+        }
+    }
+
+    pub(crate) fn push_origin(&mut self, origin: L1Origin) -> Result<()> {
+        if let Some((last, _)) = self.origins.last() {
+            if origin.number != last + 1 {
+                return Err(anyhow!(
+                    "origin of block {} expected, got {}",
+                    last + 1,
+                    origin.number
+                ));
+            }
+        }
+        self.origins.insert(&origin.number, &origin);
+        Ok(())
+    }
+
+    /// Credits to deposit in the block after `number`, when they were recorded
+    /// with it.
+    pub(crate) fn credits(&self, number: u64) -> Option<Vec<Credit>> {
+        self.credits
+            .get_value()
+            .filter(|(recorded, _)| *recorded == number)
+            .map(|(_, credits)| credits)
+    }
+
+    pub(crate) fn set_credits(&mut self, number: u64, credits: &[Credit]) {
+        self.credits.set_value(Some((number, credits.to_vec())));
+    }
+
+    /// Gas reserved for the system deposits of the first EVM block of the
+    /// BTC block `height`, when it was recorded with it.
+    pub(crate) fn system_gas(&self, height: u64) -> Option<U256> {
+        self.system_gas
+            .get_value()
+            .filter(|(recorded, _)| *recorded == height)
+            .map(|(_, gas)| gas)
+    }
+
+    pub(crate) fn set_system_gas(&mut self, height: u64, gas: U256) {
+        self.system_gas.set_value(Some((height, gas)));
+    }
+
+    /// Persists the changes, and those of the EVM runtime sharing the vsdb
+    /// backend, to disk.
+    pub(crate) fn flush(&self) {
+        vsdb::vsdb_flush();
+    }
+}
+
+impl Drop for MetaStore {
+    // the node may stop on an error between two flushes
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, sync::Once};
+
+    use rt_evm::model::types::{H160, U256};
+
+    use super::*;
+
+    fn datadir(name: &str) -> PathBuf {
+        static BASE_DIR: Once = Once::new();
+        BASE_DIR.call_once(|| {
+            vsdb::vsdb_set_base_dir(env::temp_dir().join("meta_store_vsdb")).unwrap();
+        });
+        let datadir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&datadir);
+        fs::create_dir_all(&datadir).unwrap();
+        datadir
+    }
+
+    #[test]
+    fn credits_survive_restart() {
+        let datadir = datadir("meta_store_credits");
+        let credits = vec![
+            Credit {
+                source_hash: H256::repeat_byte(1),
+                to: H160::repeat_byte(2),
+                value: U256::from(3),
+            },
+            Credit {
+                source_hash: H256::repeat_byte(4),
+                to: H160::repeat_byte(5),
+                value: U256::from(6),
+            },
+        ];
+
+        let mut store =
+            MetaStore::create(&datadir, 101, &ChainConfigSchedule::default(), None).unwrap();
+        assert_eq!(store.credits(7), None);
+        store.set_credits(7, &credits);
+        store.flush();
+
+        let store = MetaStore::load(&datadir).unwrap();
+        assert_eq!(store.credits(7), Some(credits));
+        // credits recorded with another block are not those of the latest
+        assert_eq!(store.credits(8), None);
+
+        fs::remove_dir_all(datadir).unwrap();
+    }
+
+    #[test]
+    fn open_does_not_migrate() {
+        let datadir = datadir("meta_store_open");
+        fs::write(datadir.join(LEGACY_HEIGHT_FILE), 101u64.to_be_bytes()).unwrap();
+        assert!(MetaStore::open(&datadir).is_err());
+        assert!(datadir.join(LEGACY_HEIGHT_FILE).exists());
+
+        let store =
+            MetaStore::create(&datadir, 101, &ChainConfigSchedule::default(), None).unwrap();
+        drop(store);
+        assert_eq!(MetaStore::open(&datadir).unwrap().first_height(), 101);
+
+        fs::remove_dir_all(datadir).unwrap();
+    }
+
+    fn origin(number: u64, btc_height: u64, index: u32, count: u32) -> L1Origin {
+        L1Origin {
+            number,
+            btc_height,
+            index,
+            count,
+        }
+    }
+
+    #[test]
+    fn origins_legacy_fallback() {
+        let datadir = datadir("meta_store_origins_legacy");
+        let mut store =
+            MetaStore::create(&datadir, 100, &ChainConfigSchedule::default(), None).unwrap();
+        store.load_origins(&datadir, 5).unwrap();
+
+        assert_eq!(store.origin(1), Some(origin(1, 100, 0, 1)));
+        assert_eq!(store.origin(5), Some(origin(5, 104, 0, 1)));
+        // the genesis block is anchored one below the first height
+        assert_eq!(store.origin(0), Some(origin(0, 99, 0, 1)));
+
+        fs::remove_dir_all(datadir).unwrap();
+    }
+
+    #[test]
+    fn origins_push_and_reload() {
+        let datadir = datadir("meta_store_origins_push");
+        let mut store =
+            MetaStore::create(&datadir, 100, &ChainConfigSchedule::default(), None).unwrap();
+        store.load_origins(&datadir, 5).unwrap();
+        for (i, number) in (6..10).enumerate() {
+            store
+                .push_origin(origin(number, 105 + i as u64 / 2, i as u32 % 2, 2))
+                .unwrap();
+        }
+        assert!(store.push_origin(origin(11, 107, 0, 1)).is_err());
+        assert!(store.push_origin(origin(9, 107, 0, 1)).is_err());
+        // blocks before the first record keep the legacy mapping
+        assert_eq!(store.origin(5), Some(origin(5, 104, 0, 1)));
+        assert_eq!(store.origin(6), Some(origin(6, 105, 0, 2)));
+        assert!(store.origin(6).unwrap().is_partial());
+        assert_eq!(store.origin(10), None);
+        drop(store);
+
+        // the origins above the latest block get produced again
+        let mut store = MetaStore::load(&datadir).unwrap();
+        store.load_origins(&datadir, 7).unwrap();
+        assert_eq!(store.origin(7), Some(origin(7, 105, 1, 2)));
+        assert_eq!(store.origin(8), None);
+        store.push_origin(origin(8, 106, 0, 1)).unwrap();
+        assert_eq!(store.origin(8), Some(origin(8, 106, 0, 1)));
+
+        // a latest block below the first record drops them all
+        store.load_origins(&datadir, 5).unwrap();
+        assert_eq!(store.origin(6), Some(origin(6, 105, 0, 1)));
+
+        fs::remove_dir_all(datadir).unwrap();
+    }
+}
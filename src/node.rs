@@ -1,25 +1,61 @@
-use std::{fs, mem::size_of, path::PathBuf, sync::Arc, thread::sleep, time::Duration};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
-use bitcoincore_rpc::{Auth, Client};
+use bitcoin::{hashes::Hash, BlockHash, Network, Txid};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use bridge::{
+    excluded_credit, fee_credit, gas_refunds, l1_attributes_tx, mint_credit, predeploy_txs,
+    prev_randao, reserved_fee, system_gas, system_transfer_tx, BtcHeaderPrecompile,
+    BtcMessagePrecompile, Payer, PayoutPolicy, Precompile, SchnorrPrecompile, WithdrawalStore,
+    PREDEPLOY_DEPLOYER, SYSTEM_ADDRESS,
+};
 use clap::Args;
-use config::Config;
+use config::{current_bin_hash, ChainConfig, ChainConfigSchedule, Config};
 use da::DAServiceManager;
-use fetcher::{Data, Fetcher};
+use fetcher::{Data, Fetcher, Rejection};
 use json_rpc_server::serve;
 use rpc_server::handle::NovoHandle;
 use rt_evm::{
-    model::{traits::BlockStorage, types::UnsignedTransaction},
+    executor::{ApplyBackend, Backend, RTEvmExecutor, RTEvmExecutorAdapter},
+    model::{
+        traits::{BlockStorage, Executor},
+        types::{
+            Apply, Basic, ExecutorContext, ExitReason, Log, Receipt, SignedTransaction,
+            TokenDistributionInfo, UnsignedTransaction, H160, H256, U256,
+        },
+    },
     EvmRuntime,
 };
+use tokio::{
+    runtime::{self, Runtime},
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time::sleep,
+};
+use utils::{HeaderStore, L1Origin};
+
+use crate::meta::{DatadirInfo, MetaStore};
 
 #[derive(Debug, Args)]
 pub struct Node {
     #[clap(short, long)]
     config: String,
 
-    #[clap(short, long, default_value_t = 1)]
-    start: u64,
+    /// BTC height to search the genesis config from, 1 by default; checked
+    /// against the datadir when it exists
+    #[clap(short, long)]
+    start: Option<u64>,
 
     #[clap(short, long)]
     datadir: String,
@@ -37,8 +73,10 @@ pub struct Node {
     ws_port: u16,
 }
 
-const FETCHER_HEIGHT_FILE: &str = "FETCHER_RUNTIME_height.meta";
-const FETCHER_CONFIG_FILE: &str = "FETCHER_RUNTIME_chain_cfg.meta";
+const WITHDRAWAL_FILE: &str = "BRIDGE_RUNTIME_withdrawals.meta";
+const REJECTED_FILE: &str = "FETCHER_RUNTIME_rejected.log";
+const HEADER_FILE: &str = "FETCHER_RUNTIME_headers.meta";
+const DEPOSIT_INTRINSIC_GAS: u64 = 21000;
 
 impl Node {
     pub async fn exeute(&self) -> Result<()> {
@@ -67,9 +105,19 @@ impl Node {
         )?);
 
         let datadir = PathBuf::from(&self.datadir);
+        let pinned = cfg
+            .genesis
+            .as_ref()
+            .map(|genesis| -> Result<(BlockHash, Txid)> {
+                Ok((
+                    BlockHash::from_str(&genesis.block_hash)?,
+                    Txid::from_str(&genesis.txid)?,
+                ))
+            })
+            .transpose()?;
 
         if !datadir.exists() {
-            let start = if self.start > 0 { self.start } else { 1 };
+            let start = self.start.filter(|start| *start > 0).unwrap_or(1);
 
             if let Err(e) = self
                 .init_data_dir(
@@ -77,7 +125,7 @@ impl Node {
                     da_mgr.clone(),
                     &cfg.btc.electrs_url,
                     start,
-                    0,
+                    pinned.as_ref(),
                 )
                 .await
             {
@@ -86,80 +134,443 @@ impl Node {
 
                 return Err(e);
             }
+        } else {
+            vsdb::vsdb_set_base_dir(&self.datadir).map_err(|e| anyhow!(e.to_string()))?;
         }
 
         let mut evm_rt = EvmRuntime::restore()
             .map_err(|e| anyhow!(e.to_string()))?
             .ok_or(anyhow!("restore data error"))?;
 
-        self.start_eth_api_server(&evm_rt).await?;
-        self.start_api_server(
+        log::info!("running bin hash:{:?}", current_bin_hash()?);
+
+        let mut meta = MetaStore::load(&datadir)?;
+        let first_height = meta.first_height();
+        self.check_datadir(
+            &mut meta,
+            client.clone(),
+            da_mgr.clone(),
+            &cfg.btc.electrs_url,
+            &cfg.btc.network,
+            pinned.as_ref(),
+        )
+        .await?;
+        let block_number = evm_rt
+            .copy_storage_handler()
+            .get_latest_block_header()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .number;
+        meta.load_origins(&datadir, block_number)?;
+        let latest = meta
+            .origin(block_number)
+            .ok_or(anyhow!("l1 origin of block {} not found", block_number))?;
+        // a BTC block split into several EVM blocks resumes at its next one
+        let (start, mut resume_index) = if latest.is_partial() {
+            (latest.btc_height, latest.index as usize + 1)
+        } else {
+            (latest.btc_height + 1, 0)
+        };
+
+        let schedule = meta.schedule();
+        schedule
+            .active(start)
+            .ok_or(anyhow!("chain config not found at {}", start))?
+            .check_bin_hash(start)?;
+        let schedule = Arc::new(RwLock::new(schedule));
+        let withdrawals = Arc::new(RwLock::new(WithdrawalStore::load(
+            datadir.join(WITHDRAWAL_FILE),
+        )?));
+        let payer = cfg
+            .withdrawal
+            .as_ref()
+            .map(|w| {
+                Payer::new(
+                    &cfg.btc.electrs_url,
+                    client.clone(),
+                    &w.operator_sk,
+                    &cfg.btc.network,
+                    w.confirmations,
+                    PayoutPolicy {
+                        fee_rate: w.fee_rate,
+                        max_batch: w.max_batch,
+                        bump_after: w.bump_after,
+                    },
+                )
+            })
+            .transpose()?;
+
+        let headers = Arc::new(RwLock::new(HeaderStore::load(
+            datadir.join(HEADER_FILE),
+            first_height,
+            start,
+            |height| Ok(client.get_block_header(&client.get_block_hash(height)?)?),
+        )?));
+        let precompiles: Vec<Arc<dyn Precompile>> = vec![
+            Arc::new(BtcHeaderPrecompile::new(headers.clone())),
+            Arc::new(SchnorrPrecompile),
+            Arc::new(BtcMessagePrecompile),
+        ];
+        for precompile in precompiles {
+            evm_rt
+                .register_precompile(precompile.address(), move |input, gas_limit| {
+                    precompile
+                        .execute(input, gas_limit)
+                        .map_err(|e| e.to_string())
+                })
+                .map_err(|e| anyhow!(e.to_string()))?;
+        }
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        listen_shutdown(shutdown.clone())?;
+
+        let eth_api_server = self.start_eth_api_server(&evm_rt).await?;
+        let api_server = self.start_api_server(
             da_mgr.clone(),
             client.clone(),
+            schedule.clone(),
+            withdrawals.clone(),
             cfg.btc.da_fee,
             &cfg.btc.fee_address,
             &cfg.btc.network,
         )?;
 
-        let start = {
-            let height = fs::read(datadir.join(FETCHER_HEIGHT_FILE))?;
-            let height = <[u8; size_of::<u64>()]>::try_from(height)
-                .map(u64::from_be_bytes)
-                .map_err(|_| anyhow!("start height read error"))?;
-
-            let block_number = evm_rt
-                .copy_storage_handler()
-                .get_latest_block_header()
-                .map_err(|e| anyhow!(e.to_string()))?
-                .number;
-            height + block_number
-        };
-
         let mut fetcher = Fetcher::new(
             client,
             da_mgr,
             &cfg.btc.electrs_url,
             start,
-            evm_rt.chain_id as u32,
+            schedule.clone(),
         )
         .await?;
+
+        // the credits of the latest block are deposited at the start of the
+        // next, its withdrawals are collected again in case the node stopped
+        // before. Datadirs without recorded credits get them back from the
+        // receipts, the credits of excluded deposits can not be.
+        let mut refunds = {
+            let (base_fee, sat2wei, fee_recipient, protocol_v1) = schedule
+                .read()
+                .map_err(|e| anyhow!("{}", e))?
+                .active(latest.btc_height)
+                .map(|cfg| {
+                    (
+                        cfg.base_fee,
+                        cfg.sat2wei,
+                        cfg.fee_recipient,
+                        cfg.protocol_v1(),
+                    )
+                })
+                .unwrap_or_default();
+            let (_, txs, receipts) = latest_block(&evm_rt)?;
+            if sat2wei > 0 {
+                withdrawals.write().map_err(|e| anyhow!("{}", e))?.collect(
+                    latest.btc_height,
+                    sat2wei,
+                    &receipts,
+                )?;
+            }
+            match meta.credits(block_number) {
+                Some(credits) => credits,
+                None if protocol_v1 => {
+                    log::warn!("credits of block {} not recorded", block_number);
+                    gas_refunds(&txs, &receipts, base_fee, fee_recipient)
+                }
+                None => vec![],
+            }
+        };
         log::info!("start node");
 
-        loop {
-            let (block_time, datas) = if let Ok(Some(block)) = fetcher.fetcher().await {
+        'blocks: loop {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let block = if let Ok(Some(block)) = fetcher.fetcher().await {
                 block
             } else {
-                sleep(Duration::from_secs(1));
+                sleep(Duration::from_secs(1)).await;
                 continue;
             };
+            let height = block.height;
+            let prev_hash = H256::from(block.header.prev_blockhash.to_byte_array());
+            if let Some(hash) = meta
+                .block_hash(height - 1)
+                .filter(|hash| *hash != prev_hash)
+            {
+                return Err(anyhow!(
+                    "BTC block {} {:?} processed, the chain now builds on {:?}",
+                    height - 1,
+                    hash,
+                    prev_hash
+                ));
+            }
+            let (
+                coinbase,
+                block_gas_limit,
+                base_fee,
+                sat2wei,
+                fee_recipient,
+                skip_empty_blocks,
+                protocol_v1,
+            ) = {
+                let schedule = schedule.read().map_err(|e| anyhow!("{}", e))?;
+                let chain_cfg = schedule
+                    .active(height)
+                    .ok_or(anyhow!("chain config not found at {}", height))?;
+                chain_cfg.check_bin_hash(height)?;
+                evm_rt.chain_id = chain_cfg.chain_id.into();
+                (
+                    chain_cfg.coinbase,
+                    U256::from(chain_cfg.block_gas_limit),
+                    chain_cfg.base_fee,
+                    chain_cfg.sat2wei,
+                    chain_cfg.fee_recipient,
+                    chain_cfg.skip_empty_blocks,
+                    chain_cfg.protocol_v1(),
+                )
+            };
+            // BTC header times may go backwards, the median time past does not
+            let time = {
+                let mut headers = headers.write().map_err(|e| anyhow!("{}", e))?;
+                headers.push(height, block.header)?;
+                match headers.median_time_past(height) {
+                    Some(time) if protocol_v1 => time,
+                    _ => block.time,
+                }
+            };
+            // journaled already when resuming in the middle of the BTC block
+            let resumed = resume_index > 0;
             let hdr = evm_rt
-                .generate_blockproducer(Default::default(), block_time)
+                .generate_blockproducer(coinbase, time)
                 .map_err(|e| anyhow!(e.to_string()))?;
-            let mut txs = vec![];
-            for data in datas {
+            let empty = block.datas.is_empty();
+            let mut predeploys = if protocol_v1 {
+                predeploy_txs(
+                    hdr.get_nonce(PREDEPLOY_DEPLOYER, None)
+                        .map_err(|e| anyhow!("{}", e))?,
+                    evm_rt.chain_id,
+                )
+            } else {
+                vec![]
+            };
+            // each EVM block starts with the system deposits: the L1
+            // attributes, a credit per deposit of the previous block and the
+            // fee credit. The first one also makes the predeploys and the mints
+            let reserved_gas = |transfers: usize| {
+                if protocol_v1 {
+                    system_gas(transfers)
+                } else {
+                    U256::zero()
+                }
+            };
+            let mint_count = block
+                .datas
+                .iter()
+                .filter(|data| matches!(data, Data::Mint { .. }))
+                .count();
+            // recorded to split the BTC block the same way when resuming,
+            // the credits and predeploys made since are not those of the
+            // first EVM block
+            let first_gas = match meta.system_gas(height).filter(|_| resumed) {
+                Some(gas) => gas,
+                None => {
+                    if resumed {
+                        log::warn!("system gas of BTC block {} not recorded", height);
+                    }
+                    let gas = predeploys
+                        .iter()
+                        .fold(reserved_gas(refunds.len() + mint_count + 1), |gas, tx| {
+                            gas + *tx.transaction.unsigned.gas_limit()
+                        });
+                    meta.set_system_gas(height, gas);
+                    gas
+                }
+            };
+            let mut chunks = vec![vec![]];
+            let mut chunk_gas = first_gas;
+            let mut fees = U256::zero();
+            let mut reserved = U256::zero();
+            let mut mints = vec![];
+            for data in block.datas {
                 match data {
                     Data::Config(cfg) => {
-                        fetcher.chain_id = cfg.chain_id;
-                        evm_rt.chain_id = cfg.chain_id.into();
-                        fs::write(
-                            datadir.join(FETCHER_CONFIG_FILE),
-                            serde_json::to_string_pretty(&cfg)?,
-                        )?;
+                        let mut schedule = schedule.write().map_err(|e| anyhow!("{}", e))?;
+                        let activation_height = schedule.insert(height, cfg);
+                        log::info!("chain config scheduled at {}", activation_height);
+                        if let Some(Err(e)) = schedule
+                            .active(activation_height)
+                            .map(|cfg| cfg.check_bin_hash(activation_height))
+                        {
+                            log::warn!("{}", e);
+                        }
+                        meta.set_schedule(&schedule);
                     }
-                    Data::Transaction(mut tx) => {
-                        if let UnsignedTransaction::Deposit(ref mut tx) = tx.transaction.unsigned {
-                            tx.nonce =
-                                hdr.get_nonce(tx.from, None).map_err(|e| anyhow!("{}", e))?;
+                    Data::Transaction(tx) => {
+                        let gas_limit = *tx.transaction.unsigned.gas_limit();
+                        // deposits over the block gas limit go to the next EVM
+                        // block, after the credits of the current one
+                        let fits = chunk_gas.saturating_add(gas_limit) <= block_gas_limit;
+                        let next_gas = reserved_gas(chunks.last().map_or(0, Vec::len) + 1);
+                        let checked =
+                            if !fits && next_gas.saturating_add(gas_limit) > block_gas_limit {
+                                Err(anyhow!("gas limit {} above block gas limit", gas_limit))
+                            } else {
+                                check_deposit(&tx)
+                            };
+                        if let Err(e) = checked {
+                            if !resumed {
+                                journal_exclusion(&datadir, height, &tx, &e.to_string())?;
+                            }
+                            continue;
+                        }
+                        if !fits {
+                            chunks.push(vec![]);
+                            chunk_gas = next_gas;
                         }
-                        txs.push(*tx)
+                        chunk_gas += gas_limit;
+                        reserved = reserved.saturating_add(reserved_fee(&tx, base_fee));
+                        chunks.last_mut().unwrap().push(*tx)
+                    }
+                    Data::Rejected(rejection) if !resumed => {
+                        journal_rejection(&datadir, height, &rejection)?
+                    }
+                    Data::Rejected(_) => {}
+                    Data::Fee(fee) => fees += fee,
+                    Data::Mint {
+                        btc_txid,
+                        to,
+                        value,
+                    } => mints.push(mint_credit(btc_txid, to, value)),
+                }
+            }
+            drop(hdr);
+            // the refunds are carried to the next produced block, the L1
+            // origins keep mapping the produced blocks to their BTC height
+            if empty && skip_empty_blocks {
+                log::debug!("skip empty block {}", height);
+                chunks.clear();
+            }
+
+            let count = chunks.len() as u32;
+            for (index, deposits) in chunks.into_iter().enumerate().skip(resume_index) {
+                // the L1 origins resume the rest of the BTC block
+                if shutdown.load(Ordering::SeqCst) {
+                    break 'blocks;
+                }
+                let hdr = evm_rt
+                    .generate_blockproducer(coinbase, time)
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                let mut txs = vec![];
+                let fees = fees.saturating_sub(reserved);
+                // the blocks of the legacy protocol have no system deposits,
+                // its configs have no fee recipient nor bridge address
+                let fee_recipient = fee_recipient.filter(|_| protocol_v1);
+                let mut system_nonce = hdr
+                    .get_nonce(SYSTEM_ADDRESS, None)
+                    .map_err(|e| anyhow!("{}", e))?;
+                let mut next_system_nonce = || {
+                    system_nonce += U256::one();
+                    system_nonce - U256::one()
+                };
+                if index == 0 {
+                    txs.append(&mut predeploys);
+                }
+                if protocol_v1 {
+                    txs.push(l1_attributes_tx(
+                        next_system_nonce(),
+                        height,
+                        block.hash,
+                        block.time,
+                        block.median_fee_rate,
+                        index as u32,
+                        evm_rt.chain_id,
+                    ));
+                }
+                for credit in refunds.iter() {
+                    txs.push(system_transfer_tx(
+                        next_system_nonce(),
+                        credit,
+                        evm_rt.chain_id,
+                    ));
+                }
+                // system deposits come first, the bridged value and the fee
+                // credit included
+                if index == 0 {
+                    for credit in mints.iter() {
+                        txs.push(system_transfer_tx(
+                            next_system_nonce(),
+                            credit,
+                            evm_rt.chain_id,
+                        ));
                     }
                 }
+                if let Some(fee_recipient) = fee_recipient.filter(|_| index == 0 && !fees.is_zero())
+                {
+                    let credit = fee_credit(height, fee_recipient, fees);
+                    txs.push(system_transfer_tx(
+                        next_system_nonce(),
+                        &credit,
+                        evm_rt.chain_id,
+                    ));
+                }
+                drop(hdr);
+
+                // recorded first, the origins above the latest block are
+                // dropped on restart
+                let number = evm_rt
+                    .copy_storage_handler()
+                    .get_latest_block_header()
+                    .map_err(|e| anyhow!(e.to_string()))?
+                    .number
+                    + 1;
+                meta.push_origin(L1Origin {
+                    number,
+                    btc_height: height,
+                    index: index as u32,
+                    count,
+                })?;
+
+                let randao = protocol_v1.then(|| prev_randao(block.hash, index as u32));
+                let excluded = produce_block(&evm_rt, coinbase, time, randao, txs, deposits)?;
+                for (tx, reason) in excluded.iter() {
+                    journal_exclusion(&datadir, height, tx, reason)?;
+                }
+
+                let (_, txs, receipts) = latest_block(&evm_rt)?;
+                refunds = if protocol_v1 {
+                    gas_refunds(&txs, &receipts, base_fee, fee_recipient)
+                } else {
+                    vec![]
+                };
+                if let Some(fee_recipient) = fee_recipient {
+                    refunds.extend(
+                        excluded
+                            .iter()
+                            .map(|(tx, _)| excluded_credit(tx, fee_recipient, base_fee)),
+                    );
+                }
+                meta.set_credits(number, &refunds);
+                withdrawals
+                    .write()
+                    .map_err(|e| anyhow!("{}", e))?
+                    .collect(height, sat2wei, &receipts)?;
+                meta.flush();
             }
-            log::debug!("execute transaction:{:#?}", txs);
-            log::info!("execute transaction:{}", txs.len());
+            resume_index = 0;
+            meta.set_block_hash(height, block.hash);
+            meta.flush();
 
-            hdr.produce_block(txs).map_err(|e| anyhow!(e.to_string()))?;
+            if let Some(payer) = &payer {
+                let mut store = withdrawals.write().map_err(|e| anyhow!("{}", e))?;
+                if let Err(e) = payer.pay(&mut store) {
+                    log::error!("withdrawal payout error:{}", e);
+                }
+            }
         }
+
+        api_server.abort();
+        drop(eth_api_server);
+        log::info!("node stopped at block {}", latest_block(&evm_rt)?.0);
+        Ok(())
     }
 
     async fn init_data_dir(
@@ -168,30 +579,170 @@ impl Node {
         da_mgr: Arc<DAServiceManager>,
         electrs_url: &str,
         start: u64,
-        chain_id: u32,
+        pinned: Option<&(BlockHash, Txid)>,
     ) -> Result<()> {
         log::info!("fetcher first config");
-        let (height, cfg) = Fetcher::new(client, da_mgr, electrs_url, start, chain_id)
-            .await?
-            .fetcher_first_cfg()
-            .await?;
+        let schedule = Arc::new(RwLock::new(ChainConfigSchedule::default()));
+        let mut fetcher =
+            Fetcher::new(client.clone(), da_mgr, electrs_url, start, schedule).await?;
+        let (height, txid, cfg) = match pinned {
+            Some((block_hash, txid)) => fetcher.fetcher_genesis_cfg(block_hash, Some(txid)).await?,
+            None => fetcher.fetcher_first_cfg().await?,
+        };
+        cfg.check_bin_hash(height - 1)?;
+        let chain_id = cfg.chain_id;
+        // the legacy protocol leaves the genesis state empty
+        let distributions = if cfg.protocol_v1() {
+            genesis_distributions(&cfg)
+        } else {
+            vec![]
+        };
 
         log::info!("create data dir");
         vsdb::vsdb_set_base_dir(&self.datadir).map_err(|e| anyhow!(e.to_string()))?;
         let datadir = vsdb::vsdb_get_base_dir();
 
         log::info!("init data dir");
-        EvmRuntime::restore_or_create(cfg.chain_id.into(), &[])
+        let evm_rt = EvmRuntime::restore_or_create(chain_id.into(), &distributions)
             .map_err(|e| anyhow!(e.to_string()))?;
-        fs::write(datadir.join(FETCHER_HEIGHT_FILE), u64::to_be_bytes(height))?;
-        fs::write(
-            datadir.join(FETCHER_CONFIG_FILE),
-            serde_json::to_string_pretty(&cfg)?,
-        )?;
+        if cfg.protocol_v1() {
+            seed_genesis_state(&evm_rt, &cfg)?;
+        }
+        let schedule = ChainConfigSchedule::new(height - 1, cfg);
+        let info = DatadirInfo {
+            network: client
+                .get_blockchain_info()?
+                .chain
+                .to_core_arg()
+                .to_string(),
+            start: if pinned.is_some() { height - 1 } else { start },
+            genesis_hash: H256::from(client.get_block_hash(height - 1)?.to_byte_array()),
+            genesis_txid: Some(H256::from(txid.to_byte_array())),
+            chain_id,
+        };
+        MetaStore::create(&datadir, height, &schedule, Some(info))?;
         Ok(())
     }
 
-    async fn start_eth_api_server(&self, evm_rt: &EvmRuntime) -> Result<()> {
+    /// Refuses a datadir created for another network, genesis envelope,
+    /// chain id or `--start`, the genesis envelope is decoded again from
+    /// bitcoind. One created before these were recorded gets them from the
+    /// current bitcoind.
+    async fn check_datadir(
+        &self,
+        meta: &mut MetaStore,
+        client: Arc<Client>,
+        da_mgr: Arc<DAServiceManager>,
+        electrs_url: &str,
+        network: &str,
+        pinned: Option<&(BlockHash, Txid)>,
+    ) -> Result<()> {
+        let network = Network::from_str(network)?;
+        let chain = client.get_blockchain_info()?.chain;
+        if chain != network {
+            return Err(anyhow!(
+                "bitcoind runs {}, config network is {}",
+                chain,
+                network
+            ));
+        }
+
+        let genesis_height = meta.first_height() - 1;
+        let genesis_hash = client.get_block_hash(genesis_height)?;
+        let recorded = meta.info();
+        let genesis_txid = recorded
+            .as_ref()
+            .and_then(|info| info.genesis_txid)
+            .map(|txid| Txid::from_byte_array(txid.0));
+        let schedule = Arc::new(RwLock::new(ChainConfigSchedule::default()));
+        let (_, txid, cfg) = Fetcher::new(client, da_mgr, electrs_url, genesis_height, schedule)
+            .await?
+            .fetcher_genesis_cfg(&genesis_hash, genesis_txid.as_ref())
+            .await?;
+        let current = DatadirInfo {
+            network: chain.to_core_arg().to_string(),
+            start: genesis_height,
+            genesis_hash: H256::from(genesis_hash.to_byte_array()),
+            genesis_txid: Some(H256::from(txid.to_byte_array())),
+            chain_id: cfg.chain_id,
+        };
+
+        let info = match recorded {
+            Some(info) if info.genesis_txid.is_some() => info,
+            Some(info) => {
+                log::warn!("datadir genesis tx not recorded, record {:?}", txid);
+                DatadirInfo {
+                    genesis_txid: current.genesis_txid,
+                    ..info
+                }
+            }
+            None => {
+                log::warn!("datadir info not recorded, record {:?}", current);
+                DatadirInfo {
+                    start: self.start.unwrap_or(genesis_height),
+                    ..current.clone()
+                }
+            }
+        };
+        if meta.info().as_ref() != Some(&info) {
+            meta.set_info(info.clone());
+            meta.flush();
+        }
+
+        if Network::from_core_arg(&info.network)? != chain {
+            Err(anyhow!(
+                "datadir created on {}, bitcoind runs {}",
+                info.network,
+                chain
+            ))
+        } else if info.genesis_hash != current.genesis_hash {
+            Err(anyhow!(
+                "datadir genesis is BTC block {:?} at {}, bitcoind has {:?}",
+                info.genesis_hash,
+                genesis_height,
+                current.genesis_hash
+            ))
+        } else if info.chain_id != current.chain_id {
+            Err(anyhow!(
+                "datadir created for chain id {}, genesis envelope has {}",
+                info.chain_id,
+                current.chain_id
+            ))
+        } else if let Some(pinned) = pinned
+            .map(|(block_hash, _)| H256::from(block_hash.to_byte_array()))
+            .filter(|pinned| *pinned != info.genesis_hash)
+        {
+            Err(anyhow!(
+                "config pins genesis block {:?}, datadir genesis is {:?}",
+                pinned,
+                info.genesis_hash
+            ))
+        } else if let Some(pinned) = pinned
+            .map(|(_, txid)| H256::from(txid.to_byte_array()))
+            .filter(|pinned| info.genesis_txid != Some(*pinned))
+        {
+            Err(anyhow!(
+                "config pins genesis tx {:?}, datadir genesis is {:?}",
+                pinned,
+                info.genesis_txid
+            ))
+        } else if let Some(start) = self
+            .start
+            .filter(|start| pinned.is_none() && *start != info.start)
+        {
+            Err(anyhow!(
+                "datadir created with --start {}, got {}",
+                info.start,
+                start
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Starts the eth JSON-RPC server on a runtime of its own, it is stopped
+    /// when the returned server is dropped.
+    async fn start_eth_api_server(&self, evm_rt: &EvmRuntime) -> Result<EthApiServer> {
         let http_endpoint = if 0 == self.http_port {
             None
         } else {
@@ -204,38 +755,316 @@ impl Node {
             Some(format!("{}:{}", self.listen_ip, self.ws_port))
         };
 
-        evm_rt
-            .spawn_jsonrpc_server(
-                "novolite-0.1.0",
-                http_endpoint.as_deref(),
-                ws_endpoint.as_deref(),
-            )
-            .await
-            .map_err(|e| anyhow!(e.to_string()))
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        {
+            // the server tasks are spawned on the entered runtime
+            let _guard = runtime.enter();
+            evm_rt
+                .spawn_jsonrpc_server(
+                    "novolite-0.1.0",
+                    http_endpoint.as_deref(),
+                    ws_endpoint.as_deref(),
+                )
+                .await
+                .map_err(|e| anyhow!(e.to_string()))?;
+        }
+        Ok(EthApiServer(Some(runtime)))
     }
 
     fn start_api_server(
         &self,
         da_mgr: Arc<DAServiceManager>,
         client: Arc<Client>,
+        schedule: Arc<RwLock<ChainConfigSchedule>>,
+        withdrawals: Arc<RwLock<WithdrawalStore>>,
         da_fee: u64,
         fee_address: &str,
         network: &str,
-    ) -> Result<()> {
+    ) -> Result<JoinHandle<()>> {
         let handle = NovoHandle::new(
             da_mgr.clone(),
             client.to_owned(),
+            schedule,
+            withdrawals,
             da_fee,
             fee_address,
             network,
         )?;
         let addr = format!("{}:{}", self.listen_ip, self.api_port).parse()?;
 
-        tokio::spawn(async move {
+        Ok(tokio::spawn(async move {
             if let Err(e) = serve(&addr, handle).await {
                 log::error!("api server execute error:{}", e);
             }
-        });
-        Ok(())
+        }))
     }
 }
+
+/// The eth JSON-RPC server, its runtime is shut down without blocking when
+/// dropped, on every exit path of the node.
+struct EthApiServer(Option<Runtime>);
+
+impl Drop for EthApiServer {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Requests a shutdown on SIGINT or SIGTERM, the node stops before its next
+/// EVM block.
+fn listen_shutdown(shutdown: Arc<AtomicBool>) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        log::info!("shutdown requested");
+        shutdown.store(true, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// Produces the next block, deposits failing on their own are left out and
+/// returned with the reason. Those are found by executing the deposits one at
+/// a time on a scratch copy of the latest state, a block that still fails is
+/// produced without deposits.
+fn produce_block(
+    evm_rt: &EvmRuntime,
+    coinbase: H160,
+    time: u64,
+    randao: Option<H256>,
+    system_txs: Vec<SignedTransaction>,
+    deposits: Vec<SignedTransaction>,
+) -> Result<Vec<(SignedTransaction, String)>> {
+    let (deposits, mut excluded) =
+        dry_run_deposits(evm_rt, coinbase, time, randao, &system_txs, deposits)?;
+
+    let produce = |deposits: &[SignedTransaction]| -> Result<()> {
+        let mut hdr = evm_rt
+            .generate_blockproducer(coinbase, time)
+            .map_err(|e| anyhow!(e.to_string()))?;
+        if let Some(randao) = randao {
+            hdr.set_prev_randao(randao);
+        }
+        let mut txs = system_txs.clone();
+        let mut nonces = HashMap::<H160, U256>::new();
+        for tx in deposits {
+            let mut tx = tx.clone();
+            if let UnsignedTransaction::Deposit(ref mut tx) = tx.transaction.unsigned {
+                let nonce = match nonces.get(&tx.from) {
+                    Some(nonce) => *nonce,
+                    None => hdr.get_nonce(tx.from, None).map_err(|e| anyhow!("{}", e))?,
+                };
+                nonces.insert(tx.from, nonce + 1);
+                tx.nonce = nonce;
+            }
+            txs.push(tx);
+        }
+        log::debug!("execute transaction:{:#?}", txs);
+        log::info!("execute transaction:{}", txs.len());
+
+        hdr.produce_block(txs).map_err(|e| anyhow!(e.to_string()))
+    };
+
+    if let Err(e) = produce(&deposits) {
+        log::warn!("produce block error:{}", e);
+        produce(&[])?;
+        excluded.extend(deposits.into_iter().map(|tx| (tx, e.to_string())));
+    }
+    Ok(excluded)
+}
+
+/// Deposits that executed and those that failed, with the reason.
+type DryRun = (Vec<SignedTransaction>, Vec<(SignedTransaction, String)>);
+
+/// Executes `system_txs` then each deposit in turn on a scratch copy of the
+/// latest state, in the context of the block to produce.
+fn dry_run_deposits(
+    evm_rt: &EvmRuntime,
+    coinbase: H160,
+    time: u64,
+    randao: Option<H256>,
+    system_txs: &[SignedTransaction],
+    deposits: Vec<SignedTransaction>,
+) -> Result<DryRun> {
+    let storage = evm_rt.copy_storage_handler();
+    let header = storage
+        .get_latest_block_header()
+        .map_err(|e| anyhow!(e.to_string()))?;
+    let trie = evm_rt.copy_trie_handler();
+    let mut ctx = ExecutorContext::from(header.clone());
+    ctx.block_number = U256::from(header.number + 1);
+    ctx.block_coinbase = coinbase;
+    ctx.block_timestamp = U256::from(time);
+    if let Some(randao) = randao {
+        ctx.prev_randao = randao;
+    }
+    let mut backend = RTEvmExecutorAdapter::from_root(header.state_root, &trie, &storage, ctx)
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    let executor = RTEvmExecutor::default();
+    executor.exec(&mut backend, system_txs);
+
+    let mut executed = vec![];
+    let mut excluded = vec![];
+    for mut tx in deposits {
+        if let UnsignedTransaction::Deposit(ref mut tx) = tx.transaction.unsigned {
+            tx.nonce = backend.basic(tx.from).nonce;
+        }
+        let resp = executor.exec(&mut backend, &[tx.clone()]);
+        match resp.tx_resp.first().map(|resp| &resp.exit_reason) {
+            Some(ExitReason::Fatal(e)) => excluded.push((tx, format!("{:?}", e))),
+            Some(_) => executed.push(tx),
+            None => excluded.push((tx, "not executed".to_string())),
+        }
+    }
+    Ok((executed, excluded))
+}
+
+/// Checks a deposit pays its intrinsic gas before it is put into a block, the
+/// rest is checked by executing it.
+fn check_deposit(tx: &SignedTransaction) -> Result<()> {
+    let gas_limit = *tx.transaction.unsigned.gas_limit();
+    if gas_limit < U256::from(DEPOSIT_INTRINSIC_GAS) {
+        return Err(anyhow!("gas limit {} below intrinsic gas", gas_limit));
+    }
+    Ok(())
+}
+
+/// Genesis balances of the config accounts, the runtime seeds them.
+fn genesis_distributions(cfg: &ChainConfig) -> Vec<TokenDistributionInfo> {
+    cfg.accounts
+        .iter()
+        .filter_map(|(address, account)| {
+            account.balance.map(|amount| TokenDistributionInfo {
+                account: *address,
+                amount,
+            })
+        })
+        .collect()
+}
+
+/// Applies the nonces, code and storage of the genesis accounts to the
+/// genesis state the runtime seeded with their balances, the genesis block
+/// is stored again with the new state root.
+fn seed_genesis_state(evm_rt: &EvmRuntime, cfg: &ChainConfig) -> Result<()> {
+    let applies = cfg
+        .accounts
+        .iter()
+        .filter(|(_, account)| {
+            account.nonce.is_some_and(|nonce| !nonce.is_zero())
+                || account.code.as_ref().is_some_and(|code| !code.is_empty())
+                || account.storage.as_ref().is_some_and(|s| !s.is_empty())
+        })
+        .map(|(address, account)| Apply::Modify {
+            address: *address,
+            basic: Basic {
+                balance: account.balance.unwrap_or_default(),
+                nonce: account.nonce.unwrap_or_default(),
+            },
+            code: account.code.as_ref().map(|code| code.to_vec()),
+            storage: account
+                .storage
+                .iter()
+                .flatten()
+                .map(|(key, value)| {
+                    let (mut index, mut word) = (H256::zero(), H256::zero());
+                    key.to_big_endian(index.as_bytes_mut());
+                    value.to_big_endian(word.as_bytes_mut());
+                    (index, word)
+                })
+                .collect::<Vec<_>>(),
+            reset_storage: false,
+        })
+        .collect::<Vec<_>>();
+    if applies.is_empty() {
+        return Ok(());
+    }
+    log::info!("seed {} genesis accounts", applies.len());
+
+    let storage = evm_rt.copy_storage_handler();
+    let mut block = storage
+        .get_latest_block()
+        .map_err(|e| anyhow!(e.to_string()))?;
+    let trie = evm_rt.copy_trie_handler();
+    let mut backend = RTEvmExecutorAdapter::from_root(
+        block.header.state_root,
+        &trie,
+        &storage,
+        ExecutorContext::from(block.header.clone()),
+    )
+    .map_err(|e| anyhow!(e.to_string()))?;
+    backend.apply(applies, Vec::<Log>::new(), false);
+    block.header.state_root = backend.commit();
+    storage
+        .insert_block(block)
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+fn latest_block(evm_rt: &EvmRuntime) -> Result<(u64, Vec<SignedTransaction>, Vec<Receipt>)> {
+    let storage = evm_rt.copy_storage_handler();
+    let number = storage
+        .get_latest_block_header()
+        .map_err(|e| anyhow!(e.to_string()))?
+        .number;
+    let block = storage
+        .get_block(number)
+        .map_err(|e| anyhow!(e.to_string()))?
+        .ok_or(anyhow!("block {} not found", number))?;
+
+    let txs = storage
+        .get_transactions(number, &block.tx_hashes)
+        .map_err(|e| anyhow!(e.to_string()))?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(anyhow!("block {} transaction not found", number))?;
+    let receipts = storage
+        .get_receipts(number, &block.tx_hashes)
+        .map_err(|e| anyhow!(e.to_string()))?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(anyhow!("block {} receipt not found", number))?;
+    Ok((number, txs, receipts))
+}
+
+fn journal_rejection(datadir: &Path, height: u64, rejection: &Rejection) -> Result<()> {
+    log::warn!("rejected at {}:{:?}", height, rejection);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(datadir.join(REJECTED_FILE))?;
+    let line = serde_json::json!({
+        "btc_height": height,
+        "btc_txid": rejection.btc_txid,
+        "vout": rejection.vout,
+        "reason": rejection.reason,
+    });
+    Ok(writeln!(file, "{}", line)?)
+}
+
+fn journal_exclusion(
+    datadir: &Path,
+    height: u64,
+    tx: &SignedTransaction,
+    reason: &str,
+) -> Result<()> {
+    log::warn!(
+        "excluded at {}:{:?}:{}",
+        height,
+        tx.transaction.hash,
+        reason
+    );
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(datadir.join(REJECTED_FILE))?;
+    let line = serde_json::json!({
+        "btc_height": height,
+        "tx_hash": tx.transaction.hash,
+        "reason": reason,
+    });
+    Ok(writeln!(file, "{}", line)?)
+}